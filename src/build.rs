@@ -1,24 +1,35 @@
+use std::fmt;
 use std::fs;
-use std::io::ErrorKind;
-use std::sync::atomic::Ordering::Relaxed;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
 use std::sync::Weak;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::{OsStr, OsString},
     path::{Path, PathBuf},
-    process::Command,
-    sync::{atomic::AtomicBool, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    time::Duration,
+    process::{Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use crossbeam::queue::SegQueue;
-use indicatif::{MultiProgress, ProgressBar};
-use log::{error, info};
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use log::{error, info, warn};
 use threadpool::ThreadPool;
 
+use crate::error::{Failure, Result, SuruError};
+use crate::events::{Event, Events, Listener};
 use crate::hooks::{post_compile, pre_compile, HookResult};
-use crate::once_fallible::OnceFallible;
-use crate::util::remove_suffix;
+use crate::interrupt;
+use crate::logs;
+use crate::manifest::Manifest;
+use crate::progress::{Outcome, Progress};
+use crate::state::{self, FileHash, Record, State};
+use crate::trace::{worker, Span, Trace};
+use crate::util::{load_average, output_with_usage, quote_command, remove_suffix};
 use crate::{
     parser::{Recipe, Task, TaskFile},
     util::remove_prefix,
@@ -40,61 +51,564 @@ fn file(dep: &DependencyFile) -> &String {
 #[derive(Debug)]
 struct Target {
     dependency_files: Vec<DependencyFile>,
+    /// The files each dependency was declared in.
+    origins: HashMap<String, Vec<String>>,
     dependents: Vec<Dependent>,
-    dependencies: Vec<Weak<(String, DynTarget, OnceFallible)>>,
-    is_branch: bool,
+    dependencies: Vec<Weak<(String, DynTarget, AtomicUsize)>>,
 }
 
+/// With no limit on the number of jobs, this many commands per CPU are run at
+/// most, so that a large build doesn't start a thread for every target.
+const MAX_JOBS_PER_CPU: usize = 16;
+
 type DynTarget = RwLock<Target>;
-type Dependent = Arc<(String, DynTarget, OnceFallible)>;
+/// A target, along with the number of its dependencies that haven't been
+/// built yet.
+type Dependent = Arc<(String, DynTarget, AtomicUsize)>;
 
-pub fn compile(mut input: TaskFile, builddir: &Path, sourcedir: &Path, mp: MultiProgress) {
-    input.tasks = fix_paths(input.tasks, sourcedir, builddir);
-    add_implicit(&mut input.tasks, &input.recipes, sourcedir);
-    let progress = ProgressBar::new(input.tasks.len() as u64);
-
-    mp.add(progress.clone());
-
-    let roots = get_roots(input.tasks);
-
-    let runner = leak(ThreadPool::new(num_cpus::get_physical()));
-
-    let recipes = leak(input.recipes);
-
-    let builddir = leak(builddir.to_path_buf());
-    let sourcedir = leak(sourcedir.to_path_buf());
-    let die = Arc::new(AtomicBool::new(false));
-
-    let hook_state = leak(SegQueue::new());
-
-    for arc in &roots {
-        let arc = arc.1.clone();
-        let die = die.clone();
-        let progress = progress.clone();
-        runner.execute(move || {
-            if !arc.2.is_completed() {
-                arc.2.call_once_maybe(|| {
-                    build_deps(
-                        arc.clone(),
-                        recipes,
-                        runner,
-                        &sourcedir,
-                        &builddir,
-                        die,
-                        progress,
-                        &hook_state,
-                    )
-                });
-            }
+#[derive(Default)]
+pub struct BuildOptions {
+    /// Targets to build. Every target is built if this is empty.
+    pub targets: Vec<String>,
+    /// Work out the commands that would be run instead of running them, and
+    /// return them in [`BuildReport::planned`].
+    pub dry_run: bool,
+    /// Number of commands to run at once. `Some(0)` means no limit, apart from
+    /// at most 16 per CPU, and `None` uses one job per physical core.
+    pub jobs: Option<usize>,
+    /// Don't start new commands while the load average is above this.
+    pub max_load: Option<f64>,
+    /// Keep building targets that don't depend on a failed target.
+    pub keep_going: bool,
+    /// Print why each target is built.
+    pub explain: bool,
+    /// Write a Chrome trace of every command run to this file.
+    pub trace: Option<PathBuf>,
+    /// Told about everything that happens, in place of the progress bar.
+    pub listener: Option<Arc<dyn Listener>>,
+    /// Print a `[12/340] CC lib/lib.o` line to stdout for each command run,
+    /// in place of the progress bar, such as when stdout isn't a terminal.
+    pub progress_lines: bool,
+    /// Print what the commands building each target wrote to stderr, for
+    /// targets that were built.
+    pub show_warnings: bool,
+}
+
+/// What a build did.
+#[derive(Debug, Clone, Default)]
+pub struct BuildReport {
+    /// The targets that were built, in the order they finished.
+    pub built: Vec<String>,
+    /// The targets that were already up to date.
+    pub up_to_date: Vec<String>,
+    /// Every target a dry run looked at, in the order they would be built.
+    pub planned: Vec<Planned>,
+    pub duration: Duration,
+}
+
+/// A target a dry run looked at, and the commands that build it.
+#[derive(Debug, Clone)]
+pub struct Planned {
+    pub target: String,
+    /// Why the target would be built, or `None` if it is up to date.
+    pub reason: Option<String>,
+    /// The commands that build it, quoted, whether or not it would be built.
+    pub commands: Vec<String>,
+}
+
+/// Builds the tasks in `input`. Fails with [`SuruError::Command`] if any
+/// target failed to build.
+pub fn compile(
+    input: TaskFile,
+    builddir: &Path,
+    sourcedir: &Path,
+    options: &BuildOptions,
+    mp: MultiProgress,
+) -> Result<BuildReport> {
+    let start = Instant::now();
+    let Graph {
+        targets,
+        recipes,
+        precious,
+        ..
+    } = Graph::new(input, builddir, sourcedir, &options.targets)?;
+
+    if options.dry_run {
+        return Ok(BuildReport {
+            planned: dry_run(&targets, &recipes, sourcedir, builddir)?,
+            duration: start.elapsed(),
+            ..Default::default()
+        });
+    }
+
+    let jobs = match options.jobs {
+        Some(0) => targets.len().clamp(1, num_cpus::get() * MAX_JOBS_PER_CPU),
+        Some(n) => n,
+        None => num_cpus::get_physical(),
+    };
+    if options.max_load.is_some() && load_average().is_none() {
+        warn!("Unable to read the system load average, ignoring the load limit");
+    }
+
+    let events = options.listener.clone().map(Events::new);
+    if let Some(events) = &events {
+        events.emit(Event::GraphLoaded {
+            targets: targets.len(),
+        });
+    }
+    let mp = if events.is_some() {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        mp
+    };
+    let state = State::open(builddir).map_err(SuruError::io("Unable to open the build state"))?;
+    let progress = Progress::new(
+        mp,
+        targets.keys().cloned(),
+        jobs,
+        options.progress_lines && events.is_none(),
+        state.durations(),
+    );
+
+    // Only needed to find the critical path of a trace
+    let dependencies = options.trace.as_ref().map(|_| {
+        targets
+            .iter()
+            .map(|(name, t)| {
+                let deps = read(&t.1)
+                    .dependency_files
+                    .iter()
+                    .filter(|d| matches!(d, DependencyFile::Generated(_)))
+                    .map(|d| file(d).clone())
+                    .collect();
+                (name.clone(), deps)
+            })
+            .collect::<HashMap<_, Vec<_>>>()
+    });
+
+    let total = targets.len();
+    let roots = get_roots(targets);
+
+    let manifest =
+        Manifest::open(builddir).map_err(SuruError::io("Unable to open the build manifest"))?;
+
+    let shared = Arc::new(Shared {
+        recipes,
+        precious,
+        runner: ThreadPool::new(jobs),
+        sourcedir: sourcedir.to_path_buf(),
+        builddir: builddir.to_path_buf(),
+        die: Arc::new(AtomicBool::new(false)),
+        progress,
+        hook_out: SegQueue::new(),
+        max_load: options.max_load,
+        running: AtomicUsize::new(0),
+        keep_going: options.keep_going,
+        explain: options.explain,
+        trace: options.trace.as_ref().map(|_| Trace::new()),
+        events,
+        failures: Default::default(),
+        skipped: Default::default(),
+        error: Default::default(),
+        built: Default::default(),
+        up_to_date: Default::default(),
+        warnings: Default::default(),
+        manifest,
+        state,
+    });
+    interrupt::stop_on_interrupt(&shared.die);
+
+    for (_, arc) in &roots {
+        schedule(arc.clone(), &shared);
+    }
+
+    shared.runner.join();
+    post_compile(&shared.hook_out, &shared.builddir);
+    shared
+        .manifest
+        .add_file(&shared.builddir.join("compile_commands.json"));
+
+    shared.progress.finish();
+
+    if let (Some(trace), Some(path), Some(dependencies)) =
+        (&shared.trace, &options.trace, &dependencies)
+    {
+        if let Err(e) = trace.write(path) {
+            error!("Unable to write the trace to {:?}: {}", path, e);
+        }
+        eprint!("{}", trace.summary(dependencies));
+    }
+
+    if options.show_warnings {
+        report_warnings(&shared);
+    }
+
+    let finished = shared.built.lock().expect("Built list poisoned").len()
+        + shared.up_to_date.lock().expect("Built list poisoned").len();
+    let error = shared.error.lock().expect("Error poisoned").take();
+    let result = match error {
+        _ if interrupt::interrupted() => Err(SuruError::Interrupted),
+        Some(e) => Err(e),
+        // The pool catches panics, so they'd otherwise go unnoticed
+        None if shared.runner.panic_count() > 0 => Err(SuruError::Internal(format!(
+            "{} build job(s) panicked",
+            shared.runner.panic_count()
+        ))),
+        None => report_failures(&shared).and_then(|()| match total - finished {
+            0 => Ok(()),
+            unbuilt => Err(SuruError::Internal(format!(
+                "{} target(s) were never built",
+                unbuilt
+            ))),
+        }),
+    };
+    if let Some(events) = &shared.events {
+        events.emit(Event::BuildFinished {
+            success: result.is_ok(),
+            failed: match &result {
+                Err(SuruError::Command(failures)) => failures.len(),
+                _ => 0,
+            },
+            duration: events.elapsed().as_secs_f64(),
         });
     }
+    result?;
+    let built = std::mem::take(&mut *shared.built.lock().expect("Built list poisoned"));
+    let up_to_date = std::mem::take(&mut *shared.up_to_date.lock().expect("Built list poisoned"));
+    Ok(BuildReport {
+        built,
+        up_to_date,
+        duration: start.elapsed(),
+        ..Default::default()
+    })
+}
 
-    runner.join();
-    post_compile(hook_state, &builddir);
+/// State shared by every job of a build.
+struct Shared {
+    recipes: HashMap<String, Vec<Recipe>>,
+    /// Targets that aren't deleted when the command building them fails.
+    precious: HashSet<String>,
+    runner: ThreadPool,
+    sourcedir: PathBuf,
+    builddir: PathBuf,
+    die: Arc<AtomicBool>,
+    progress: Progress,
+    hook_out: SegQueue<HookResult>,
+    max_load: Option<f64>,
+    /// Number of commands currently running.
+    running: AtomicUsize,
+    keep_going: bool,
+    explain: bool,
+    trace: Option<Trace>,
+    events: Option<Events>,
+    failures: Mutex<Vec<Failure>>,
+    /// Targets that weren't built, mapped to the failed target they depend on.
+    skipped: Mutex<HashMap<String, String>>,
+    /// What stopped the build, if it wasn't a command failing.
+    error: Mutex<Option<SuruError>>,
+    built: Mutex<Vec<String>>,
+    up_to_date: Mutex<Vec<String>>,
+    /// What the commands building each target wrote to stderr, for targets
+    /// that were built successfully.
+    warnings: Mutex<Vec<(String, String)>>,
+    manifest: Manifest,
+    state: State,
 }
 
-fn leak<T>(t: T) -> &'static T {
-    Box::leak(Box::new(t))
+/// Why a command failed.
+#[derive(Debug)]
+enum CommandError {
+    Failed {
+        status: ExitStatus,
+        stderr: String,
+    },
+    Unable(io::Error),
+    /// suru was interrupted while the command was running.
+    Interrupted,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Failed { status, stderr } => {
+                write!(f, "Build failure code {}:\n{}", status, stderr)
+            }
+            CommandError::Unable(e) => write!(f, "Unable to run command: {}", e),
+            CommandError::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+fn fail(target: &Dependent, failure: Failure, shared: &Shared) {
+    if !interrupt::interrupted() {
+        error!("Failed to build {}", target.0);
+    }
+    if shared.keep_going {
+        let mut skipped = shared.skipped.lock().expect("Failure list poisoned");
+        let mut pending = read_s(&target.1).dependents.clone();
+        while let Some(dep) = pending.pop() {
+            if !skipped.contains_key(&dep.0) {
+                skipped.insert(dep.0.clone(), target.0.clone());
+                shared.progress.finished(&dep.0, Outcome::Skipped);
+                if let Some(events) = &shared.events {
+                    events.emit(Event::Skipped {
+                        target: dep.0.clone(),
+                        dependency: target.0.clone(),
+                    });
+                }
+                pending.extend(read_s(&dep.1).dependents.iter().cloned());
+            }
+        }
+    } else {
+        shared.die.store(true, Relaxed);
+    }
+    shared
+        .failures
+        .lock()
+        .expect("Failure list poisoned")
+        .push(failure);
+}
+
+/// Stops the build because of something other than a command failing. Only
+/// the first error is kept.
+fn abort(error: SuruError, shared: &Shared) {
+    shared.die.store(true, Relaxed);
+    shared
+        .error
+        .lock()
+        .expect("Error poisoned")
+        .get_or_insert(error);
+}
+
+fn report_warnings(shared: &Shared) {
+    let mut warnings = std::mem::take(&mut *shared.warnings.lock().expect("Warnings poisoned"));
+    warnings.sort();
+    for (target, output) in warnings {
+        eprintln!("Warnings from {}:\n{}", target, output.trim_end());
+    }
+}
+
+fn report_failures(shared: &Shared) -> Result<()> {
+    let failures = std::mem::take(&mut *shared.failures.lock().expect("Failure list poisoned"));
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    let mut skipped: Vec<_> = shared
+        .skipped
+        .lock()
+        .expect("Failure list poisoned")
+        .iter()
+        .map(|(t, cause)| (t.clone(), cause.clone()))
+        .collect();
+    skipped.sort();
+    for (target, cause) in skipped {
+        warn!("Skipped {} because {} failed", target, cause);
+    }
+
+    for failure in failures.iter() {
+        error!(
+            "{}: {}\n{}",
+            failure.target, failure.command, failure.output
+        );
+    }
+    Err(SuruError::Command(failures))
+}
+
+/// Queues a target whose dependencies have all been built, and once it is
+/// built, queues each dependent that was only waiting on it.
+///
+/// Every target starts out counting the targets it depends on, and is queued
+/// by whichever dependency brings that count to zero, or up front if it has
+/// none. A count only reaches zero once, so no target is built twice, and
+/// every target is built unless one of its dependencies fails, the build is
+/// stopped, or it depends on itself.
+fn schedule(target: Dependent, shared: &Arc<Shared>) {
+    let job = shared.clone();
+    shared.runner.execute(move || {
+        if build_target(&target, &job) {
+            for dependent in &read_s(&target.1).dependents {
+                if dependent.2.fetch_sub(1, AcqRel) == 1 {
+                    schedule(dependent.clone(), &job);
+                }
+            }
+        }
+    });
+}
+
+/// Makes the paths in `input` relative to the source and build directories and
+/// adds the tasks implied by recipes. If any targets are given, only the tasks
+/// needed to build them are kept.
+fn resolve_tasks(
+    mut input: TaskFile,
+    builddir: &Path,
+    sourcedir: &Path,
+    targets: &[String],
+) -> Result<TaskFile> {
+    input.tasks = fix_paths(input.tasks, sourcedir, builddir);
+    input.precious = input
+        .precious
+        .into_iter()
+        .map(|t| decannonicalize(t, sourcedir, builddir))
+        .collect();
+    let targets: Vec<_> = targets
+        .iter()
+        .map(|t| decannonicalize(t.clone(), sourcedir, builddir))
+        .collect();
+    add_implicit(&mut input.tasks, &input.recipes, sourcedir, &targets)?;
+    if !targets.is_empty() {
+        input.tasks = select_targets(input.tasks, &targets, sourcedir)?;
+    }
+    Ok(input)
+}
+
+/// The build graph, for inspecting what a build would do without running it.
+pub struct Graph {
+    targets: HashMap<String, Dependent>,
+    recipes: HashMap<String, Vec<Recipe>>,
+    precious: HashSet<String>,
+    sourcedir: PathBuf,
+    builddir: PathBuf,
+}
+
+/// A file that a target depends on.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Input {
+    pub file: String,
+    /// Whether the file is built by another target, rather than being a source.
+    pub generated: bool,
+    /// The files the dependency was declared in.
+    pub origins: Vec<String>,
+}
+
+/// The recipe chosen to build a target, and why.
+#[derive(Debug)]
+pub struct RecipeMatch {
+    pub recipe: String,
+    /// The inputs that made the recipe match, along with the pattern they matched.
+    pub matched: Vec<(String, String)>,
+    /// Earlier candidates that none of the inputs matched.
+    pub rejected: Vec<String>,
+    pub commands: Vec<String>,
+}
+
+impl Graph {
+    pub fn new(
+        input: TaskFile,
+        builddir: &Path,
+        sourcedir: &Path,
+        targets: &[String],
+    ) -> Result<Self> {
+        let input = resolve_tasks(input, builddir, sourcedir, targets)?;
+        let linked = link_targets(input.tasks);
+        if let Some(cycle) = find_cycle(&linked) {
+            return Err(cycle_error(&linked, &cycle));
+        }
+        Ok(Self {
+            targets: linked,
+            recipes: input.recipes,
+            precious: input.precious,
+            sourcedir: sourcedir.to_path_buf(),
+            builddir: builddir.to_path_buf(),
+        })
+    }
+
+    /// Converts a path given by the user into the name the graph uses for it.
+    pub fn name(&self, file: &str) -> String {
+        decannonicalize(file.to_owned(), &self.sourcedir, &self.builddir)
+    }
+
+    pub fn contains(&self, target: &str) -> bool {
+        self.targets.contains_key(target)
+    }
+
+    /// Every target, sorted by name.
+    pub fn targets(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.targets.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    pub fn dependencies(&self, target: &str) -> Option<Vec<Input>> {
+        let target = read(&self.targets.get(target)?.1);
+        let inputs = target
+            .dependency_files
+            .iter()
+            .map(|d| Input {
+                file: file(d).clone(),
+                generated: matches!(d, DependencyFile::Generated(_)),
+                origins: target.origins.get(file(d)).cloned().unwrap_or_default(),
+            })
+            .collect();
+        Some(inputs)
+    }
+
+    /// The paths of every source that a target depends on, sorted.
+    pub fn sources(&self) -> Vec<PathBuf> {
+        let mut sources: Vec<_> = self
+            .targets
+            .values()
+            .flat_map(|t| {
+                read(&t.1)
+                    .dependency_files
+                    .iter()
+                    .filter(|d| matches!(d, DependencyFile::Source(_)))
+                    .map(|d| append_dep(d, &self.sourcedir, &self.builddir))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        sources.sort();
+        sources.dedup();
+        sources
+    }
+
+    /// The targets that directly depend on a file, sorted by name.
+    pub fn dependents(&self, file_name: &str) -> Vec<&str> {
+        let mut dependents: Vec<_> = self
+            .targets
+            .iter()
+            .filter(|(_, t)| {
+                read(&t.1)
+                    .dependency_files
+                    .iter()
+                    .any(|d| file(d) == file_name)
+            })
+            .map(|(name, _)| name.as_str())
+            .collect();
+        dependents.sort();
+        dependents
+    }
+
+    pub fn recipe(&self, target: &str) -> Option<RecipeMatch> {
+        let t = read(&self.targets.get(target)?.1);
+        let (key, candidates) = candidate_recipes(target, &self.recipes)?;
+        let mut rejected = Vec::new();
+        for recipe in candidates {
+            let matched: Vec<_> = t
+                .dependency_files
+                .iter()
+                .filter_map(|d| Some((file(d).clone(), listed_by(file(d), target, recipe)?)))
+                .collect();
+            if matched.is_empty() {
+                rejected.push(recipe.describe(key));
+                continue;
+            }
+            let commands = expand_steps(
+                target,
+                &t.dependency_files,
+                recipe,
+                &self.sourcedir,
+                &self.builddir,
+            );
+            return Some(RecipeMatch {
+                recipe: recipe.describe(key),
+                matched,
+                rejected,
+                commands: commands.steps.iter().map(|s| quote_command(s)).collect(),
+            });
+        }
+        None
+    }
 }
 
 fn write(d: &DynTarget) -> RwLockWriteGuard<'_, Target> {
@@ -109,23 +623,37 @@ fn fix_paths(
     sourcedir: &Path,
     builddir: &Path,
 ) -> HashMap<String, Task> {
-    HashMap::from_iter(tasks.into_iter().map(|(f, t)| {
-        (
-            decannonicalize(f, sourcedir, builddir),
-            Task {
-                inputs: t
-                    .inputs
-                    .into_iter()
-                    .map(|d| decannonicalize(d, sourcedir, builddir))
-                    .collect(),
-            },
-        )
-    }))
+    let mut fixed: HashMap<String, Task> = HashMap::new();
+    for (f, t) in tasks {
+        // Depfiles name targets by absolute path, so the same target can show up
+        // under two names until now
+        let task = fixed
+            .entry(decannonicalize(f, sourcedir, builddir))
+            .or_default();
+        for input in t.inputs {
+            let input = decannonicalize(input, sourcedir, builddir);
+            if !task.inputs.contains(&input) {
+                task.inputs.push(input);
+            }
+        }
+        for (input, origins) in t.origins {
+            let existing = task
+                .origins
+                .entry(decannonicalize(input, sourcedir, builddir))
+                .or_default();
+            for origin in origins {
+                if !existing.contains(&origin) {
+                    existing.push(origin);
+                }
+            }
+        }
+    }
+    fixed
 }
 
-fn decannonicalize(s: String, sourcedir: &Path, builddir: &Path) -> String {
+pub(crate) fn decannonicalize(s: String, sourcedir: &Path, builddir: &Path) -> String {
     let n = Path::new(&s);
-    if n.starts_with(&builddir) {
+    if n.starts_with(builddir) {
         return n
             .strip_prefix(builddir)
             .unwrap()
@@ -134,7 +662,7 @@ fn decannonicalize(s: String, sourcedir: &Path, builddir: &Path) -> String {
             .unwrap()
             .to_owned();
     }
-    if n.starts_with(&sourcedir) {
+    if n.starts_with(sourcedir) {
         return n
             .strip_prefix(sourcedir)
             .unwrap()
@@ -150,19 +678,48 @@ fn add_implicit(
     tasks: &mut HashMap<String, Task>,
     recipes: &HashMap<String, Vec<Recipe>>,
     sourcedir: &Path,
-) {
+    requested: &[String],
+) -> Result<()> {
     let mut implicit = Vec::new();
-    for (_, task) in tasks.iter() {
-        for dep in &task.inputs {
-            if !tasks.contains_key(dep) && !sourcedir.join(dep).exists() {
-                if let Some(r) = recipes.get(remove_prefix(dep)) {
-                    implicit.push((
-                        dep.clone(),
-                        Task {
-                            inputs: determine_deps(dep, &r, sourcedir),
-                        },
-                    ));
-                }
+    for dep in tasks.values().flat_map(|t| &t.inputs).chain(requested) {
+        if !tasks.contains_key(dep) && !sourcedir.join(dep).exists() {
+            if let Some(r) = recipes.get(remove_prefix(dep)) {
+                let ext = remove_prefix(dep);
+                let describe = |recipes: &[&Recipe]| {
+                    recipes
+                        .iter()
+                        .map(|r| r.describe(ext))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                let (inputs, recipe) = match determine_deps(dep, r, sourcedir) {
+                    Ok(found) => found,
+                    Err(results) if results.is_empty() => {
+                        return Err(SuruError::Graph(format!(
+                            "No recipe can build {}, the inputs of {} don't exist",
+                            dep,
+                            describe(&r.iter().collect::<Vec<_>>())
+                        )));
+                    }
+                    Err(results) => {
+                        return Err(SuruError::Graph(format!(
+                            "More than one recipe can build {}: {}",
+                            dep,
+                            describe(&results)
+                        )));
+                    }
+                };
+                let origin = format!("recipe {}", recipe.describe(ext));
+                implicit.push((
+                    dep.clone(),
+                    Task {
+                        origins: inputs
+                            .iter()
+                            .map(|i| (i.clone(), vec![origin.clone()]))
+                            .collect(),
+                        inputs,
+                    },
+                ));
             }
         }
     }
@@ -170,9 +727,48 @@ fn add_implicit(
     for (k, v) in implicit {
         tasks.insert(k, v);
     }
+    Ok(())
+}
+
+/// Cuts the task graph down to the requested targets and everything they
+/// transitively depend on.
+fn select_targets(
+    mut tasks: HashMap<String, Task>,
+    targets: &[String],
+    sourcedir: &Path,
+) -> Result<HashMap<String, Task>> {
+    for target in targets {
+        if !tasks.contains_key(target) {
+            if sourcedir.join(target).exists() {
+                info!("Nothing to be done for {}", target);
+            } else {
+                return Err(SuruError::Usage(format!(
+                    "No task or recipe to build {}",
+                    target
+                )));
+            }
+        }
+    }
+
+    let mut selected = HashMap::new();
+    let mut pending = targets.to_vec();
+    while let Some(target) = pending.pop() {
+        if let Some(task) = tasks.remove(&target) {
+            pending.extend(task.inputs.iter().cloned());
+            selected.insert(target, task);
+        }
+    }
+    Ok(selected)
 }
 
-fn determine_deps<'a>(filename: &str, recipes: &[Recipe], sourcedir: &Path) -> Vec<String> {
+/// Finds the one recipe that can build a file that no task builds, along with
+/// the inputs it needs. Otherwise returns the recipes that could, of which there
+/// are either none or too many.
+pub(crate) fn determine_deps<'a>(
+    filename: &str,
+    recipes: &'a [Recipe],
+    sourcedir: &Path,
+) -> Result<(Vec<String>, &'a Recipe), Vec<&'a Recipe>> {
     let results: Vec<_> = recipes
         .iter()
         .filter(|r| {
@@ -183,21 +779,19 @@ fn determine_deps<'a>(filename: &str, recipes: &[Recipe], sourcedir: &Path) -> V
             })
         })
         .collect();
-    if results.len() > 1 {
-        error!("Multiple valid recipes for {}: {:?}", filename, recipes);
-        panic!();
-    } else if let [s] = results[..] {
-        s.templ_in
+    if let [s] = results[..] {
+        let inputs = s
+            .templ_in
             .iter()
             .map(|ext| remove_suffix(filename).to_string() + "." + ext)
-            .collect()
+            .collect();
+        Ok((inputs, s))
     } else {
-        error!("No valid recipes for {}", filename);
-        panic!();
+        Err(results)
     }
 }
 
-fn get_roots(tasks: HashMap<String, Task>) -> Vec<(String, Dependent)> {
+fn link_targets(tasks: HashMap<String, Task>) -> HashMap<String, Dependent> {
     let unprocessed: HashMap<String, Dependent> =
         HashMap::from_iter(tasks.into_iter().map(|(file, deps)| {
             (
@@ -210,17 +804,16 @@ fn get_roots(tasks: HashMap<String, Task>) -> Vec<(String, Dependent)> {
                             .into_iter()
                             .map(DependencyFile::Source)
                             .collect(),
+                        origins: deps.origins,
                         dependents: Default::default(),
                         dependencies: Default::default(),
-                        is_branch: false,
                     }),
-                    OnceFallible::new(),
+                    AtomicUsize::new(0),
                 )),
             )
         }));
-    for (_, target_deps) in &unprocessed {
+    for target_deps in unprocessed.values() {
         let mut td = write(&target_deps.1);
-        let mut is_branch = false;
         let mut deps: Vec<_> = td
             .dependency_files
             .iter_mut()
@@ -228,191 +821,490 @@ fn get_roots(tasks: HashMap<String, Task>) -> Vec<(String, Dependent)> {
                 if let Some(d) = unprocessed.get(file(dep)) {
                     *dep = DependencyFile::Generated(file(dep).clone());
                     write(&d.1).dependents.push(target_deps.clone());
-                    is_branch = true;
                     return Some(Arc::downgrade(d));
                 }
                 None
             })
             .collect();
         td.dependencies.append(&mut deps);
-        if is_branch {
-            td.is_branch = true;
-        }
+        target_deps.2.store(td.dependencies.len(), Relaxed);
     }
 
     unprocessed
+}
+
+fn get_roots(targets: HashMap<String, Dependent>) -> Vec<(String, Dependent)> {
+    targets
         .into_iter()
-        .filter(|(_, b)| !read(&b.1).is_branch)
+        .filter(|(_, b)| b.2.load(Relaxed) == 0)
         .collect()
 }
 
-fn read_s(d: &DynTarget) -> RwLockReadGuard<'_, Target> {
-    d.read().expect("This section is read only")
+/// Finds a target that depends on itself, returning the targets along the cycle,
+/// starting and ending with that target.
+fn find_cycle(targets: &HashMap<String, Dependent>) -> Option<Vec<String>> {
+    fn visit(
+        target: &Dependent,
+        done: &mut HashSet<String>,
+        path: &mut Vec<String>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|t| *t == target.0) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(target.0.clone());
+            return Some(cycle);
+        }
+        if done.contains(&target.0) {
+            return None;
+        }
+        path.push(target.0.clone());
+        for dep in &read(&target.1).dependencies {
+            let dep = dep.upgrade().expect("Dependency unexpectedly dropped");
+            if let Some(cycle) = visit(&dep, done, path) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.insert(target.0.clone());
+        None
+    }
+
+    let mut names: Vec<_> = targets.keys().collect();
+    names.sort();
+    let mut done = HashSet::new();
+    names
+        .into_iter()
+        .find_map(|name| visit(&targets[name], &mut done, &mut Vec::new()))
 }
 
-fn build_deps(
-    target: Dependent,
-    recipes: &'static HashMap<String, Vec<Recipe>>,
-    runner: &'static ThreadPool,
-    sourcedir: &'static Path,
-    builddir: &'static Path,
-    die: Arc<AtomicBool>,
-    progress: ProgressBar,
-    hook_out: &'static SegQueue<HookResult>,
-) -> bool {
-    if die.load(Relaxed) {
-        return false;
+/// Describes a cycle, along with the files that declared each dependency in it.
+fn cycle_error(targets: &HashMap<String, Dependent>, cycle: &[String]) -> SuruError {
+    let mut message = format!("Dependency cycle: {}", cycle.join(" -> "));
+    for edge in cycle.windows(2) {
+        let target = read(&targets[&edge[0]].1);
+        let origins = target.origins.get(&edge[1]).cloned().unwrap_or_default();
+        message += &format!(
+            "\n  {} depends on {}, from {}",
+            edge[0],
+            edge[1],
+            origins.join(", ")
+        );
     }
+    SuruError::Graph(message)
+}
 
-    for dep in &read_s(&target.1).dependencies {
-        let dep = dep.upgrade().expect("Dependency unexpectedly dropped");
-        if !dep.2.is_completed() {
-            return false; // just give up if we're not ready yet
+/// Orders targets so that every target comes after all of its dependencies.
+fn topo_order(targets: &HashMap<String, Dependent>) -> Vec<Dependent> {
+    fn visit(target: &Dependent, visited: &mut HashSet<String>, order: &mut Vec<Dependent>) {
+        if !visited.insert(target.0.clone()) {
+            return;
+        }
+        for dep in &read(&target.1).dependencies {
+            let dep = dep.upgrade().expect("Dependency unexpectedly dropped");
+            visit(&dep, visited, order);
         }
+        order.push(target.clone());
     }
 
-    let a = recipes
-        .get(remove_prefix(&target.0))
-        .or_else(|| recipes.get("%"));
-    if let Some(rs) = a {
-        run_recipe(
-            &target.0,
-            &read_s(&target.1).dependency_files,
-            rs,
+    let mut names: Vec<_> = targets.keys().collect();
+    names.sort();
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for name in names {
+        visit(&targets[name], &mut visited, &mut order);
+    }
+    order
+}
+
+fn dry_run(
+    targets: &HashMap<String, Dependent>,
+    recipes: &HashMap<String, Vec<Recipe>>,
+    sourcedir: &Path,
+    builddir: &Path,
+) -> Result<Vec<Planned>> {
+    let records = state::read(builddir).map_err(SuruError::io("Unable to read the build state"))?;
+    let mut rebuilt = HashSet::new();
+    let mut planned = Vec::new();
+    for target in topo_order(targets) {
+        let t = read(&target.1);
+        let recipe = find_recipe(&target.0, &t.dependency_files, recipes)
+            .ok_or_else(|| no_recipe(&target.0))?;
+        let commands = expand_steps(&target.0, &t.dependency_files, recipe, sourcedir, builddir);
+
+        let stale_dep = t
+            .dependencies
+            .iter()
+            .map(|d| d.upgrade().expect("Dependency unexpectedly dropped"))
+            .find(|d| rebuilt.contains(&d.0));
+        let quoted: Vec<_> = commands.steps.iter().map(|s| quote_command(s)).collect();
+        let reason = needs_compiling(
+            &commands.target_file,
+            &t.dependency_files,
             sourcedir,
             builddir,
-            die.clone(),
-            &progress,
-            hook_out,
-        );
-    } else {
-        panic!(
-            "Could not find a recipe to build {}\n Recipes: {:?}",
-            target.0, recipes
         )
+        .map_err(unable_to_check(&target.0))?
+        .or_else(|| commands_changed(records.get(&target.0), &quoted))
+        .map(|reason| format!("out of date, {}", reason))
+        .or_else(|| stale_dep.map(|dep| format!("rebuilt after {}", dep.0)));
+        if reason.is_some() {
+            rebuilt.insert(target.0.clone());
+        }
+
+        planned.push(Planned {
+            target: target.0.clone(),
+            reason,
+            commands: quoted,
+        });
     }
+    Ok(planned)
+}
 
-    std::thread::sleep(Duration::from_millis(100));
-
-    let target = read_s(&target.1);
-    for arc in &target.dependents {
-        let arc = arc.clone();
-        let die = die.clone();
-        let progress = progress.clone();
-        runner.execute(move || {
-            if !arc.2.is_completed() {
-                arc.2.call_once_maybe(|| {
-                    build_deps(
-                        arc.clone(),
-                        recipes,
-                        runner,
-                        sourcedir,
-                        builddir,
-                        die,
-                        progress,
-                        hook_out,
-                    )
-                });
+fn no_recipe(target: &str) -> SuruError {
+    SuruError::Graph(format!(
+        "No recipe can build {}, none of them match its inputs",
+        target
+    ))
+}
+
+fn unable_to_check(target: &str) -> impl FnOnce(io::Error) -> SuruError {
+    SuruError::io(format!("Unable to check whether {} is up to date", target))
+}
+
+fn read_s(d: &DynTarget) -> RwLockReadGuard<'_, Target> {
+    d.read().expect("This section is read only")
+}
+
+/// Builds a target whose dependencies have all been built, returning whether it
+/// succeeded.
+fn build_target(target: &Dependent, shared: &Shared) -> bool {
+    if shared.die.load(Relaxed) {
+        return false;
+    }
+
+    let dependency_files = &read_s(&target.1).dependency_files;
+    let Some(recipe) = find_recipe(&target.0, dependency_files, &shared.recipes) else {
+        abort(no_recipe(&target.0), shared);
+        return false;
+    };
+    match run_recipe(&target.0, dependency_files, recipe, shared) {
+        Ok(()) => {}
+        Err(SuruError::Command(failures)) => {
+            for failure in failures {
+                fail(target, failure, shared);
             }
-        });
+            return false;
+        }
+        Err(e) => {
+            abort(e, shared);
+            return false;
+        }
     }
+
     true
 }
 
 fn run_recipe(
     target: &str,
     dependencies: &[DependencyFile],
-    recipes: &[Recipe],
-    sourcedir: &Path,
-    builddir: &Path,
-    mut die: Arc<AtomicBool>,
-    progress: &ProgressBar,
-    hook_out: &'static SegQueue<HookResult>,
-) {
-    let recipe = recipes
-        .iter()
-        .find(|r| {
-            dependencies
-                .iter()
-                .any(|d| is_dep_listed(file(d), target, r))
-        })
-        .unwrap_or_else(|| {
-            die.store(true, Relaxed);
-            panic!(
-                "Unable to find recipe to build {:?} for {}, Recipes:{:#?}",
-                dependencies, target, recipes
-            );
+    recipe: &Recipe,
+    shared: &Shared,
+) -> Result<()> {
+    let Shared {
+        sourcedir,
+        builddir,
+        ..
+    } = shared;
+    let RecipeCommands {
+        target_file,
+        dep_paths,
+        steps,
+    } = expand_steps(target, dependencies, recipe, sourcedir, builddir);
+    let commands: Vec<_> = steps.iter().map(|s| quote_command(s)).collect();
+    let mut changed = commands_changed(shared.state.get(target).as_ref(), &commands);
+    let mut started = None;
+    let mut log = Vec::new();
+    let mut warnings = String::new();
+    for step in steps {
+        pre_compile(&shared.hook_out, &step, &dep_paths, &target_file, sourcedir);
+
+        let reason = match needs_compiling(&target_file, dependencies, sourcedir, builddir)
+            .map_err(unable_to_check(target))?
+        {
+            // Like a missing target, changed commands only make the first
+            // step run
+            None if started.is_none() => changed.take(),
+            reason => reason,
+        };
+        if let Some(reason) = reason {
+            if let (Some(events), None) = (&shared.events, started) {
+                events.emit(Event::TargetStarted {
+                    target: target.to_owned(),
+                    reason: reason.to_string(),
+                });
+            }
+            started.get_or_insert_with(Instant::now);
+            if shared.explain {
+                // Not logged, so that it shows up no matter the log level
+                shared
+                    .progress
+                    .suspend(|| eprintln!("Building {} because {}", target, reason));
+            }
+            let command = quote_command(&step);
+            if let Some(events) = &shared.events {
+                events.emit(Event::CommandRun {
+                    target: target.to_owned(),
+                    command: command.clone(),
+                });
+            }
+            log.extend_from_slice(format!("$ {}\n", command).as_bytes());
+            shared.progress.started(target, &step);
+            let stderr = execute(step, target, &target_file, shared, &mut log).map_err(|e| {
+                save_log(target, &log, shared);
+                shared.progress.finished(target, Outcome::Failed);
+                if let Some(events) = &shared.events {
+                    let (status, stderr) = match &e {
+                        CommandError::Failed { status, stderr } => (status.code(), stderr.clone()),
+                        CommandError::Unable(e) => (None, e.to_string()),
+                        CommandError::Interrupted => (None, e.to_string()),
+                    };
+                    events.emit(Event::TargetFailed {
+                        target: target.to_owned(),
+                        command: command.clone(),
+                        status,
+                        stderr,
+                    });
+                }
+                SuruError::Command(vec![Failure {
+                    target: target.to_owned(),
+                    command,
+                    output: e.to_string(),
+                }])
+            })?;
+            warnings.push_str(&stderr);
+        }
+    }
+    if let Some(start) = started {
+        save_log(target, &log, shared);
+        record_build(target, dependencies, commands, start.elapsed(), shared);
+        if !warnings.trim().is_empty() {
+            shared
+                .warnings
+                .lock()
+                .expect("Warnings poisoned")
+                .push((target.to_owned(), warnings));
+        }
+    }
+    let list = if started.is_some() {
+        &shared.built
+    } else {
+        &shared.up_to_date
+    };
+    list.lock()
+        .expect("Built list poisoned")
+        .push(target.to_owned());
+    shared.progress.finished(
+        target,
+        match started {
+            Some(_) => Outcome::Built,
+            None => Outcome::UpToDate,
+        },
+    );
+    if let Some(events) = &shared.events {
+        events.emit(match started {
+            Some(start) => Event::TargetFinished {
+                target: target.to_owned(),
+                duration: start.elapsed().as_secs_f64(),
+            },
+            None => Event::UpToDate {
+                target: target.to_owned(),
+            },
         });
-    for step in &recipe.steps {
-        let mut step = step.iter().map(|s| s.into()).collect();
-        let target_file = builddir.join(target);
-        let dep_paths: Vec<_> = dependencies
-            .into_iter()
-            .filter(|d| is_dep_listed(file(d), target, recipe))
-            .map(|d| append_dep(d, sourcedir, builddir))
-            .collect();
-        do_replacements(
-            &mut step,
-            &target_file,
-            &dep_paths,
-            builddir.as_os_str(),
-            sourcedir.as_os_str(),
-        );
+    }
+    Ok(())
+}
+
+/// The recipes that could build a target, along with the extension they are
+/// declared for.
+fn candidate_recipes<'a, 'b>(
+    target: &'b str,
+    recipes: &'a HashMap<String, Vec<Recipe>>,
+) -> Option<(&'b str, &'a [Recipe])> {
+    let ext = remove_prefix(target);
+    match recipes.get(ext) {
+        Some(r) => Some((ext, r)),
+        None => recipes.get("%").map(|r| ("%", &r[..])),
+    }
+}
 
-        pre_compile(hook_out, &step, &dep_paths, &target_file, sourcedir);
+fn find_recipe<'a>(
+    target: &str,
+    dependencies: &[DependencyFile],
+    recipes: &'a HashMap<String, Vec<Recipe>>,
+) -> Option<&'a Recipe> {
+    candidate_recipes(target, recipes)?.1.iter().find(|r| {
+        dependencies
+            .iter()
+            .any(|d| is_dep_listed(file(d), target, r))
+    })
+}
 
-        if needs_compiling(&target_file, &dependencies, sourcedir, builddir).unwrap_or_else(|e| {
-            die.store(true, Relaxed);
-            panic!(
-                "IO error when trying to access metadata for {:?}: {}",
-                target_file, e
+/// The recipe that builds a target, given the names of its inputs.
+pub(crate) fn recipe_for<'a>(
+    target: &str,
+    inputs: &[String],
+    recipes: &'a HashMap<String, Vec<Recipe>>,
+) -> Option<&'a Recipe> {
+    let dependencies: Vec<_> = inputs.iter().cloned().map(DependencyFile::Source).collect();
+    find_recipe(target, &dependencies, recipes)
+}
+
+struct RecipeCommands {
+    target_file: PathBuf,
+    dep_paths: Vec<PathBuf>,
+    steps: Vec<Vec<OsString>>,
+}
+
+fn expand_steps(
+    target: &str,
+    dependencies: &[DependencyFile],
+    recipe: &Recipe,
+    sourcedir: &Path,
+    builddir: &Path,
+) -> RecipeCommands {
+    let target_file = builddir.join(target);
+    let dep_paths: Vec<_> = dependencies
+        .iter()
+        .filter(|d| is_dep_listed(file(d), target, recipe))
+        .map(|d| append_dep(d, sourcedir, builddir))
+        .collect();
+    let steps = recipe
+        .steps
+        .iter()
+        .map(|step| {
+            let mut step = step.iter().map(|s| s.into()).collect();
+            do_replacements(
+                &mut step,
+                &target_file,
+                &dep_paths,
+                builddir.as_os_str(),
+                sourcedir.as_os_str(),
             );
-        }) {
-            execute(step, builddir, &mut die, &target_file);
-            progress.tick();
-        }
+            step
+        })
+        .collect();
+    RecipeCommands {
+        target_file,
+        dep_paths,
+        steps,
     }
 }
 
 fn is_dep_listed(dep: &str, target: &str, recipe: &Recipe) -> bool {
-    recipe
+    listed_by(dep, target, recipe).is_some()
+}
+
+/// Returns the pattern of `recipe` that a dependency matches, if any.
+fn listed_by(dep: &str, target: &str, recipe: &Recipe) -> Option<String> {
+    if let Some(ext) = recipe
         .templ_in
         .iter()
-        .map(|ext| remove_suffix(target).to_string() + "." + ext)
-        .any(|f| f == dep)
-        || recipe.any_in.iter().any(|ext| dep.ends_with(ext))
+        .find(|ext| remove_suffix(target).to_string() + "." + ext == dep)
+    {
+        return Some(format!("%.{}", ext));
+    }
+    recipe
+        .any_in
+        .iter()
+        .find(|ext| dep.ends_with(ext.as_str()))
+        .map(|ext| format!("*.{}", ext))
+}
+
+/// Why a target has to be built.
+#[derive(Debug, PartialEq, Eq)]
+enum Reason {
+    /// The target doesn't exist yet.
+    Missing,
+    /// A dependency doesn't exist, so the target is built to find out why.
+    DependencyMissing(String),
+    /// A dependency was modified after the target was built.
+    Newer {
+        dependency: String,
+        modified: SystemTime,
+        built: SystemTime,
+    },
+    /// The commands that build the target aren't the ones it was last built
+    /// with.
+    CommandsChanged { previous: Vec<String> },
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Missing => write!(f, "it does not exist"),
+            Reason::DependencyMissing(dep) => write!(f, "{} does not exist", dep),
+            Reason::Newer {
+                dependency,
+                modified,
+                built,
+            } => write!(
+                f,
+                "{} was modified at {}, after it was built at {}",
+                dependency,
+                humantime::format_rfc3339_micros(*modified),
+                humantime::format_rfc3339_micros(*built)
+            ),
+            Reason::CommandsChanged { previous } => write!(
+                f,
+                "its commands changed since it was built with {}",
+                previous.join(" && ")
+            ),
+        }
+    }
 }
 
+/// Returns why `target` is out of date, or `None` if it is up to date.
 fn needs_compiling(
     target: &Path,
     dependencies: &[DependencyFile],
     sourcedir: &Path,
     builddir: &Path,
-) -> Result<bool, std::io::Error> {
+) -> Result<Option<Reason>, std::io::Error> {
     if !target.exists() {
-        return Ok(true);
+        return Ok(Some(Reason::Missing));
     }
     let updatetime = target.metadata()?.modified()?;
-    for dep in dependencies
-        .iter()
-        .map(|d| append_dep(d, sourcedir, builddir))
-    {
-        let a = dep.metadata();
+    for d in dependencies {
+        let a = append_dep(d, sourcedir, builddir).metadata();
         match a {
             Ok(dep) => {
-                if dep.modified()? > updatetime {
-                    return Ok(true);
+                let modified = dep.modified()?;
+                if modified > updatetime {
+                    return Ok(Some(Reason::Newer {
+                        dependency: file(d).clone(),
+                        modified,
+                        built: updatetime,
+                    }));
                 }
             }
             Err(e) => {
                 if e.kind() == ErrorKind::NotFound {
-                    return Ok(true);
+                    return Ok(Some(Reason::DependencyMissing(file(d).clone())));
                 }
                 return Err(e);
             }
         }
     }
-    Ok(false)
+    Ok(None)
+}
+
+/// Returns why a target has to be built if the commands that build it changed
+/// since it was last built. Targets suru has no record of are assumed to have
+/// been built with the same commands.
+fn commands_changed(record: Option<&Record>, commands: &[String]) -> Option<Reason> {
+    let record = record?;
+    (record.commands != commands).then(|| Reason::CommandsChanged {
+        previous: record.commands.clone(),
+    })
 }
 
 fn append_dep(dep: &DependencyFile, sourcedir: &Path, builddir: &Path) -> PathBuf {
@@ -422,53 +1314,154 @@ fn append_dep(dep: &DependencyFile, sourcedir: &Path, builddir: &Path) -> PathBu
     }
 }
 
+/// Blocks while the system load average is above the limit, as long as some
+/// other command is still running.
+fn wait_for_load(shared: &Shared) {
+    if let Some(max) = shared.max_load {
+        while shared.running.load(Acquire) > 0
+            && !shared.die.load(Relaxed)
+            && load_average().is_some_and(|l| l > max)
+        {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+/// Keeps everything the commands building a target wrote in its log.
+fn save_log(target: &str, log: &[u8], shared: &Shared) {
+    if let Err(e) = logs::write(&shared.builddir, target, log) {
+        warn!("Unable to write the log for {}: {}", target, e);
+    }
+}
+
+/// Remembers how a target was just built in the build state.
+fn record_build(
+    target: &str,
+    dependencies: &[DependencyFile],
+    commands: Vec<String>,
+    duration: Duration,
+    shared: &Shared,
+) {
+    let inputs = dependencies
+        .iter()
+        .map(|d| FileHash {
+            file: file(d).clone(),
+            hash: state::hash_file(&append_dep(d, &shared.sourcedir, &shared.builddir)).ok(),
+        })
+        .collect();
+    let record = Record {
+        target: target.to_owned(),
+        commands,
+        inputs,
+        duration: duration.as_secs_f64(),
+    };
+    if let Err(e) = shared.state.record(record) {
+        warn!("Unable to record how {} was built: {}", target, e);
+    }
+}
+
+/// Runs one step of a recipe, adding everything it writes to `log`. Returns
+/// what it wrote to stderr.
 fn execute(
     mut command: Vec<OsString>,
-    working_dir: &Path,
-    die: &mut Arc<AtomicBool>,
+    name: &str,
     target: &Path,
-) {
+    shared: &Shared,
+    log: &mut Vec<u8>,
+) -> Result<String, CommandError> {
+    wait_for_load(shared);
+
     info!("Executing command {:?}", command);
+    let quoted = shared.trace.as_ref().map(|_| quote_command(&command));
     let cmd = command.remove(0);
 
-    fs::create_dir_all(target.parent().unwrap()).unwrap_or_else(|e| {
-        error!(
+    let parent = target.parent().unwrap();
+    let missing: Vec<_> = parent.ancestors().take_while(|p| !p.exists()).collect();
+    match fs::create_dir_all(parent) {
+        Ok(()) => missing.iter().for_each(|d| shared.manifest.add_dir(d)),
+        Err(e) => error!(
             "Unable to create parent directories for {:?} due to:\n{}",
             target, e
-        );
-    });
+        ),
+    }
+
+    // Compilers write depfiles next to their output. Those are only recorded if
+    // they didn't exist before, so a source file is never mistaken for one.
+    let depfile = target.with_extension("d");
+    let had_depfile = depfile.exists();
 
-    let results = Command::new(&cmd)
-        .args(&command)
-        .current_dir(
-            working_dir
+    let start = shared.trace.as_ref().map(Trace::now);
+    shared.running.fetch_add(1, AcqRel);
+    let results = output_with_usage(
+        Command::new(&cmd).args(&command).current_dir(
+            shared
+                .builddir
                 .canonicalize()
-                .expect("Unable to cannonicalize rootdir"),
-        )
-        .output();
+                .map_err(CommandError::Unable)?,
+        ),
+    );
+    shared.running.fetch_sub(1, AcqRel);
+    if let (Some(trace), Some(start), Some(command)) = (&shared.trace, start, quoted) {
+        let (status, usage) = match &results {
+            Ok((out, usage)) => (out.status.code(), *usage),
+            Err(_) => (None, None),
+        };
+        trace.record(Span {
+            target: name.to_owned(),
+            command,
+            worker: worker(),
+            start,
+            end: trace.now(),
+            status,
+            usage,
+        });
+    }
+    match &results {
+        Ok((out, _)) => {
+            log.extend_from_slice(&out.stdout);
+            log.extend_from_slice(&out.stderr);
+            if !out.status.success() {
+                log.extend_from_slice(format!("Failed with {}\n", out.status).as_bytes());
+            }
+        }
+        Err(e) => log.extend_from_slice(format!("Unable to run command: {}\n", e).as_bytes()),
+    }
+    let succeeded = results.as_ref().is_ok_and(|(out, _)| out.status.success());
+    if !succeeded && !shared.precious.contains(name) {
+        // Whatever the command wrote is likely incomplete, and would look up
+        // to date next time since it is newer than the inputs
+        if fs::remove_file(target).is_ok() && !interrupt::interrupted() {
+            warn!("Deleted {:?} since the command building it failed", target);
+        }
+        if !had_depfile {
+            let _ = fs::remove_file(&depfile);
+        }
+    }
+    if !succeeded && interrupt::interrupted() {
+        return Err(CommandError::Interrupted);
+    }
+    if target.exists() {
+        shared.manifest.add_file(target);
+    }
+    if !had_depfile && depfile.exists() {
+        shared.manifest.add_file(&depfile);
+    }
     match results {
-        Ok(out) => {
+        Ok((out, _)) => {
             if !out.status.success() {
-                die.store(true, Relaxed);
                 error!("Error running command {:?} {:?}", &cmd, &command);
-                panic!(
-                    "Build failure code {}:\n{}",
-                    out.status,
-                    String::from_utf8_lossy(&out.stderr)
-                );
+                return Err(CommandError::Failed {
+                    status: out.status,
+                    stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+                });
             }
-            info!(
-                "Building {:?}:\n  {}",
-                target,
-                String::from_utf8_lossy(&out.stderr)
-            );
+            let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+            info!("Building {:?}:\n  {}", target, stderr);
+            Ok(stderr)
         }
         Err(e) => {
-            die.store(true, Relaxed);
-            panic!(
-                "Error running command when running {:?} {:?}:\n{:?}",
-                &cmd, &command, e
-            )
+            error!("Error running command {:?} {:?}", &cmd, &command);
+            Err(CommandError::Unable(e))
         }
     }
 }
@@ -497,3 +1490,207 @@ fn do_replacements(
         );
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::util::{make_svec, TempDir};
+
+    use super::*;
+
+    #[test]
+    fn test_select_targets() {
+        let tasks = HashMap::from([
+            (
+                "a".to_string(),
+                Task {
+                    inputs: make_svec(&["main.o", "lib/lib.o"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "b".to_string(),
+                Task {
+                    inputs: make_svec(&["other.o"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "lib/lib.o".to_string(),
+                Task {
+                    inputs: make_svec(&["lib/lib.c", "config.h"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "config.h".to_string(),
+                Task {
+                    inputs: make_svec(&["config.h.in"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "other.o".to_string(),
+                Task {
+                    inputs: make_svec(&["other.c"]),
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let error =
+            select_targets(tasks.clone(), &make_svec(&["nope.o"]), Path::new(".")).unwrap_err();
+        assert_eq!(error.to_string(), "No task or recipe to build nope.o");
+        assert_eq!(error.exit_code(), 2);
+
+        let selected = select_targets(tasks, &make_svec(&["lib/lib.o"]), Path::new(".")).unwrap();
+        let mut names: Vec<_> = selected.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, make_svec(&["config.h", "lib/lib.o"]));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let task = |inputs: &[&str], origin: &str| Task {
+            inputs: make_svec(inputs),
+            origins: inputs
+                .iter()
+                .map(|i| (i.to_string(), make_svec(&[origin])))
+                .collect(),
+        };
+        let tasks = HashMap::from([
+            ("a".to_string(), task(&["a.o"], "tasks.su")),
+            ("a.o".to_string(), task(&["a.c", "gen.h"], "a.d")),
+            ("gen.h".to_string(), task(&["tool"], "tasks.su")),
+            ("tool".to_string(), task(&["tool.c", "a.o"], "gen.su")),
+        ]);
+        let input = TaskFile {
+            tasks,
+            ..Default::default()
+        };
+        let Err(error) = Graph::new(input, Path::new("/build"), Path::new("/src"), &[]) else {
+            panic!("The cycle wasn't found");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Dependency cycle: a.o -> gen.h -> tool -> a.o\n  \
+             a.o depends on gen.h, from a.d\n  \
+             gen.h depends on tool, from tasks.su\n  \
+             tool depends on a.o, from gen.su"
+        );
+        assert_eq!(error.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let temp = TempDir::new("schedule");
+        let dir = temp.path();
+        let mut sufile =
+            "% < *.out\n\ttouch $@\n%.out < *.mid\n\tcp $^ $@\n%.mid < *.txt\n\tcp $^ $@\n"
+                .to_string();
+        let outs: Vec<_> = (0..50).map(|i| format!("{}.out", i)).collect();
+        sufile += &format!("all: {}\n", outs.join(" "));
+        for i in 0..50 {
+            fs::write(dir.join(format!("{}.txt", i)), "").unwrap();
+            sufile += &format!("{i}.out: {i}.mid\n{i}.mid: {i}.txt\n");
+        }
+        fs::write(dir.join("tasks.su"), sufile).unwrap();
+
+        let report = crate::Build::new().source_dir(dir).jobs(8).run().unwrap();
+        assert_eq!(report.built.len(), 101);
+        let position = |t: &str| report.built.iter().position(|b| b == t).unwrap();
+        for i in 0..50 {
+            assert!(position(&format!("{}.mid", i)) < position(&format!("{}.out", i)));
+        }
+        assert_eq!(report.built.last().unwrap(), "all");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failed_targets_deleted() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = TempDir::new("failed");
+        let dir = temp.path();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join("b.txt"), "").unwrap();
+        let script = dir.join("fail.sh");
+        fs::write(&script, "#!/bin/sh\necho partial > \"$1\"\nexit 1\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(
+            dir.join("tasks.su"),
+            format!(
+                concat!(
+                    "a.out: a.txt\nb.out: b.txt\nc.stamp: a.out\nprecious b.out\n",
+                    "%.out < %.txt\n\t{} $@\n%.stamp < *.out\n\ttouch $@\n"
+                ),
+                script.display()
+            ),
+        )
+        .unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let result = crate::Build::new()
+            .source_dir(dir)
+            .keep_going(true)
+            .listener(move |event: &Event| seen.lock().unwrap().push(event.clone()))
+            .run();
+        assert!(matches!(result, Err(SuruError::Command(f)) if f.len() == 2));
+        assert!(!dir.join("a.out").exists());
+        assert!(dir.join("b.out").exists());
+        assert!(events.lock().unwrap().contains(&Event::Skipped {
+            target: "c.stamp".to_owned(),
+            dependency: "a.out".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn test_needs_compiling() {
+        let temp = TempDir::new("explain");
+        let dir = temp.path();
+        let deps = [
+            DependencyFile::Source("main.c".into()),
+            DependencyFile::Generated("config.h".into()),
+        ];
+        let target = dir.join("main.o");
+        assert_eq!(
+            needs_compiling(&target, &deps, dir, dir).unwrap(),
+            Some(Reason::Missing)
+        );
+
+        let built = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let modified = built + Duration::from_secs(1);
+        for (file, time) in [("main.o", built), ("main.c", built), ("config.h", modified)] {
+            fs::File::create(dir.join(file))
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        }
+        assert_eq!(
+            needs_compiling(&target, &deps, dir, dir).unwrap(),
+            Some(Reason::Newer {
+                dependency: "config.h".into(),
+                modified,
+                built
+            })
+        );
+
+        fs::remove_file(dir.join("main.c")).unwrap();
+        assert_eq!(
+            needs_compiling(&target, &deps, dir, dir).unwrap(),
+            Some(Reason::DependencyMissing("main.c".into()))
+        );
+
+        fs::File::create(dir.join("main.c"))
+            .unwrap()
+            .set_modified(built)
+            .unwrap();
+        fs::File::options()
+            .write(true)
+            .open(dir.join("config.h"))
+            .unwrap()
+            .set_modified(built)
+            .unwrap();
+        assert_eq!(needs_compiling(&target, &deps, dir, dir).unwrap(), None);
+    }
+}
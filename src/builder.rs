@@ -0,0 +1,265 @@
+use std::{path::PathBuf, sync::Arc};
+
+use indicatif::{MultiProgress, ProgressDrawTarget};
+
+use crate::build::{compile, BuildOptions, BuildReport, Graph};
+use crate::error::Result;
+use crate::events::Listener;
+use crate::load::load;
+use crate::parser::{Context, TaskFile};
+
+/// Builds a sufile from another program, such as a `build.rs` script.
+///
+/// ```no_run
+/// let report = suru::Build::new()
+///     .source_dir("native")
+///     .build_dir(std::env::var("OUT_DIR").unwrap())
+///     .var("CFLAGS", "-O2 -fPIC")
+///     .target("libnative.a")
+///     .rerun_if_changed(true)
+///     .run()?;
+/// println!("Built {} target(s)", report.built.len());
+/// # Ok::<(), suru::error::SuruError>(())
+/// ```
+#[derive(Default)]
+pub struct Build {
+    sourcedir: Option<PathBuf>,
+    builddir: Option<PathBuf>,
+    sufile: Option<PathBuf>,
+    profile: Option<String>,
+    context: Context,
+    options: BuildOptions,
+    progress: Option<MultiProgress>,
+    rerun_if_changed: bool,
+}
+
+impl Build {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The directory the sources are in, and where `tasks.su` is read from.
+    /// Defaults to the current directory.
+    pub fn source_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.sourcedir = Some(dir.into());
+        self
+    }
+
+    /// Defaults to the source directory, or the profile's subdirectory of it
+    /// when a profile is used.
+    pub fn build_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.builddir = Some(dir.into());
+        self
+    }
+
+    /// Reads this sufile instead of `tasks.su` in the source directory.
+    pub fn file(mut self, sufile: impl Into<PathBuf>) -> Self {
+        self.sufile = Some(sufile.into());
+        self
+    }
+
+    /// Uses the variables declared in this profile in the sufile.
+    pub fn profile(mut self, name: impl Into<String>) -> Self {
+        self.profile = Some(name.into());
+        self
+    }
+
+    /// Sets a variable, overriding declarations in the sufile and the profile.
+    /// The value is split on whitespace, like `NAME=VALUE` on the command line.
+    pub fn var(mut self, name: impl Into<String>, value: &str) -> Self {
+        self.context.set_override(
+            name.into(),
+            value.split_whitespace().map(str::to_owned).collect(),
+        );
+        self
+    }
+
+    /// Builds this target. Every target is built if none are given.
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.options.targets.push(target.into());
+        self
+    }
+
+    pub fn targets<T: Into<String>>(mut self, targets: impl IntoIterator<Item = T>) -> Self {
+        self.options
+            .targets
+            .extend(targets.into_iter().map(Into::into));
+        self
+    }
+
+    /// Number of commands to run at once, 0 for no limit. Defaults to one per
+    /// physical core.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.options.jobs = Some(jobs);
+        self
+    }
+
+    /// Keep building targets that don't depend on a failed target.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.options.keep_going = keep_going;
+        self
+    }
+
+    /// Works out the commands that would be run, returning them in
+    /// [`BuildReport::planned`], without running any.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// Tells `listener` about everything that happens during the build.
+    pub fn listener(mut self, listener: impl Listener + 'static) -> Self {
+        self.options.listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Draws the progress bar here. Nothing is drawn by default.
+    pub fn progress(mut self, progress: MultiProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Prints `cargo:rerun-if-changed` for the sufile and every source after
+    /// building, so that a `build.rs` script runs again when any of them
+    /// change.
+    pub fn rerun_if_changed(mut self, rerun_if_changed: bool) -> Self {
+        self.rerun_if_changed = rerun_if_changed;
+        self
+    }
+
+    pub fn run(&self) -> Result<BuildReport> {
+        let progress = self
+            .progress
+            .clone()
+            .unwrap_or_else(|| MultiProgress::with_draw_target(ProgressDrawTarget::hidden()));
+        let report = compile(
+            self.load()?,
+            &self.builddir(),
+            &self.sourcedir(),
+            &self.options,
+            progress,
+        )?;
+        if self.rerun_if_changed {
+            for file in self.sources()? {
+                println!("cargo:rerun-if-changed={}", file.display());
+            }
+        }
+        Ok(report)
+    }
+
+    /// The sufile and every source the targets depend on, including those
+    /// named in depfiles from earlier builds.
+    pub fn sources(&self) -> Result<Vec<PathBuf>> {
+        let graph = Graph::new(
+            self.load()?,
+            &self.builddir(),
+            &self.sourcedir(),
+            &self.options.targets,
+        )?;
+        let mut sources = graph.sources();
+        sources.push(self.sufile());
+        Ok(sources)
+    }
+
+    fn load(&self) -> Result<TaskFile> {
+        load(
+            &self.sufile(),
+            self.profile.as_deref(),
+            &self.builddir(),
+            self.context.clone(),
+        )
+    }
+
+    fn sourcedir(&self) -> PathBuf {
+        self.sourcedir.clone().unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    fn builddir(&self) -> PathBuf {
+        match (&self.builddir, &self.profile) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(profile)) => self.sourcedir().join(profile),
+            (None, None) => self.sourcedir(),
+        }
+    }
+
+    fn sufile(&self) -> PathBuf {
+        self.sufile
+            .clone()
+            .unwrap_or_else(|| self.sourcedir().join("tasks.su"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::events::Event;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_build() {
+        let temp = TempDir::new("builder");
+        let dir = temp.path();
+        let builddir = dir.join("build");
+        fs::write(dir.join("in.txt"), "").unwrap();
+        fs::write(
+            dir.join("tasks.su"),
+            "COPY = false\nout: in.txt\n% < *.txt\n\t$(COPY) $^ $@\n",
+        )
+        .unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let build = Build::new()
+            .source_dir(dir)
+            .build_dir(&builddir)
+            .var("COPY", "cp")
+            .target("out")
+            .listener(move |event: &Event| seen.lock().unwrap().push(event.clone()));
+
+        let dry_run = || {
+            Build::new()
+                .source_dir(dir)
+                .build_dir(&builddir)
+                .var("COPY", "cp")
+                .dry_run(true)
+                .run()
+                .unwrap()
+                .planned
+        };
+        let planned = dry_run();
+        assert_eq!(planned.len(), 1);
+        assert_eq!(
+            planned[0].reason.as_deref(),
+            Some("out of date, it does not exist")
+        );
+        assert!(planned[0].commands[0].starts_with("cp "));
+        assert!(!builddir.join("out").exists());
+
+        let report = build.run().unwrap();
+        assert_eq!(report.built, ["out"]);
+        assert!(builddir.join("out").exists());
+        assert_eq!(
+            events.lock().unwrap().first(),
+            Some(&Event::GraphLoaded { targets: 1 })
+        );
+
+        let report = build.run().unwrap();
+        assert!(report.built.is_empty());
+        assert_eq!(report.up_to_date, ["out"]);
+        assert_eq!(dry_run()[0].reason, None);
+        assert_eq!(
+            build.sources().unwrap(),
+            [dir.join("in.txt"), dir.join("tasks.su")]
+        );
+
+        // Changing the command rebuilds the target, even though it is newer
+        // than its input
+        let report = build.var("COPY", "cp -p").run().unwrap();
+        assert_eq!(report.built, ["out"]);
+    }
+}
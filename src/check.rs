@@ -0,0 +1,640 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+use pest::iterators::Pair;
+
+use crate::build::{decannonicalize, determine_deps, recipe_for};
+use crate::error::{Result, SuruError};
+use crate::parser::{
+    eval_expr, parse_statement, statements, Context, Recipe, Rule, TaskFile, PROLOGUE,
+};
+use crate::util::remove_prefix;
+
+/// Something wrong with a sufile, found without building anything.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Prologue,
+    Depfile,
+    Sufile,
+}
+
+/// A file with its continued lines joined, which remembers where the joins
+/// were so that problems can point at the lines as written.
+struct Source<'a> {
+    name: &'a str,
+    kind: Kind,
+    text: String,
+    /// Where each continuation was, as offsets into `text`.
+    continuations: Vec<usize>,
+}
+
+impl<'a> Source<'a> {
+    fn new(name: &'a str, input: &str, kind: Kind) -> Self {
+        let mut text = String::with_capacity(input.len() + 1);
+        let mut continuations = Vec::new();
+        let mut rest = input;
+        while let Some(i) = rest.find('\\') {
+            text.push_str(&rest[..i]);
+            let after = &rest[i + 1..];
+            let newline = if after.starts_with('\n') {
+                1
+            } else if after.starts_with("\r\n") {
+                2
+            } else {
+                0
+            };
+            if newline == 0 {
+                text.push('\\');
+            } else {
+                continuations.push(text.len());
+                text.push(' ');
+            }
+            rest = &after[newline..];
+        }
+        text.push_str(rest);
+        text.push('\n');
+        Self {
+            name,
+            kind,
+            text,
+            continuations,
+        }
+    }
+
+    fn line(&self, pair: &Pair<Rule>) -> usize {
+        let pos = pair.as_span().start();
+        let joined = self.continuations.iter().take_while(|&&c| c < pos).count();
+        self.text[..pos].matches('\n').count() + joined + 1
+    }
+}
+
+/// A task statement, with its target and inputs evaluated.
+struct Declaration {
+    file: String,
+    kind: Kind,
+    line: usize,
+    target: String,
+    inputs: Vec<String>,
+}
+
+/// A variable declaration that nothing has used yet.
+struct Unused {
+    file: String,
+    line: usize,
+    /// Whether the variable was used before this declaration, which it has no
+    /// effect on.
+    used_before: bool,
+}
+
+#[derive(Default)]
+struct Checker {
+    /// The variables set on the command line and by the profile.
+    initial: Context,
+    context: Context,
+    taskfile: TaskFile,
+    problems: Vec<Problem>,
+    /// Where each variable was first declared outside of a profile.
+    declared: HashMap<String, (String, usize)>,
+    /// Variables used before they were declared, and where.
+    undeclared: Vec<(String, String, usize)>,
+    unused: HashMap<String, Unused>,
+    profile_vars: Vec<(String, String, usize)>,
+    used: HashSet<String>,
+    declarations: Vec<Declaration>,
+    /// Where each recipe was declared, by the extension it builds and its
+    /// position among the recipes for that extension.
+    recipes: HashMap<(String, usize), (String, Kind, usize)>,
+    /// Targets marked precious, and where.
+    precious: Vec<(String, usize, String)>,
+}
+
+/// Checks a sufile, along with the prologue and the depfiles read before it,
+/// without running anything. `context` holds the variables set on the command
+/// line and by the profile. Returns the problems found, in the order the files
+/// are read.
+pub fn check(
+    sufile: &str,
+    text: &str,
+    depfiles: &[(String, String)],
+    context: Context,
+    sourcedir: &Path,
+    builddir: &Path,
+) -> Result<Vec<Problem>> {
+    let mut files = vec![Source::new("prologue", PROLOGUE, Kind::Prologue)];
+    files.extend(
+        depfiles
+            .iter()
+            .map(|(name, text)| Source::new(name, text, Kind::Depfile)),
+    );
+    files.push(Source::new(sufile, text, Kind::Sufile));
+
+    let mut checker = Checker {
+        initial: context.clone(),
+        context,
+        ..Default::default()
+    };
+    for file in &files {
+        checker.file(file)?;
+    }
+    checker.variables();
+    checker.graph(sourcedir, builddir);
+
+    let order: HashMap<_, _> = files.iter().enumerate().map(|(i, f)| (f.name, i)).collect();
+    let mut problems = checker.problems;
+    problems.sort_by_key(|p| (order.get(p.file.as_str()).copied(), p.line));
+    Ok(problems)
+}
+
+/// Finds the variables used by a statement, along with where they are used.
+fn references<'a>(pair: Pair<'a, Rule>, out: &mut Vec<(&'a str, Pair<'a, Rule>)>) {
+    if pair.as_rule() != Rule::expr {
+        for inner in pair.into_inner() {
+            references(inner, out);
+        }
+        return;
+    }
+    let mut inner = pair.clone().into_inner();
+    match inner.len() {
+        0 => {}
+        1 => out.push((inner.next().unwrap().as_str(), pair)),
+        // A function, whose name isn't a variable
+        _ => {
+            inner.next();
+            for arg in inner {
+                references(arg, out);
+            }
+        }
+    }
+}
+
+impl Checker {
+    fn problem(&mut self, file: &str, line: usize, message: String) {
+        self.problems.push(Problem {
+            file: file.to_owned(),
+            line,
+            message,
+        });
+    }
+
+    fn file(&mut self, file: &Source) -> Result<()> {
+        for statement in statements(&file.text, file.name)? {
+            if statement.as_rule() == Rule::profile {
+                self.profile(file, statement);
+                continue;
+            }
+
+            let mut refs = Vec::new();
+            references(statement.clone(), &mut refs);
+            let mut defined = true;
+            for (name, pair) in refs {
+                self.used.insert(name.to_owned());
+                self.unused.remove(name);
+                if self.context.get(name).is_none() {
+                    let line = file.line(&pair);
+                    self.undeclared
+                        .push((name.to_owned(), file.name.to_owned(), line));
+                    defined = false;
+                }
+            }
+
+            let line = file.line(&statement);
+            match statement.as_rule() {
+                Rule::vardecl => {
+                    let name = statement.clone().into_inner().next().unwrap().as_str();
+                    self.declare(file, name, line);
+                    if !defined {
+                        // Stops every use of it being reported as well
+                        self.context.insert(name.to_owned(), Vec::new());
+                        continue;
+                    }
+                }
+                Rule::task if defined => {
+                    let mut inner = statement.clone().into_inner();
+                    let target = match eval_expr(&inner.next().unwrap(), &self.context) {
+                        Ok(target) => target,
+                        Err(e) => {
+                            self.problem(file.name, line, e.to_string());
+                            continue;
+                        }
+                    };
+                    let [target] = &target[..] else {
+                        let message = format!(
+                            "The target expands to {} words, a task has to have exactly one target",
+                            target.len()
+                        );
+                        self.problem(file.name, line, message);
+                        continue;
+                    };
+                    let inputs: Result<Vec<_>> =
+                        inner.map(|e| eval_expr(&e, &self.context)).collect();
+                    match inputs {
+                        Ok(inputs) => self.declarations.push(Declaration {
+                            file: file.name.to_owned(),
+                            kind: file.kind,
+                            line,
+                            target: target.clone(),
+                            inputs: inputs.concat(),
+                        }),
+                        Err(e) => {
+                            self.problem(file.name, line, e.to_string());
+                            continue;
+                        }
+                    }
+                }
+                Rule::recipe if defined => {
+                    let pattern = statement.clone().into_inner().next().unwrap();
+                    let key = remove_prefix(pattern.as_str()).to_owned();
+                    let index = self.taskfile.recipes.get(&key).map_or(0, Vec::len);
+                    self.recipes
+                        .insert((key, index), (file.name.to_owned(), file.kind, line));
+                }
+                Rule::precious if defined => {
+                    for target in statement.clone().into_inner() {
+                        // Errors are reported when the statement is parsed below
+                        if let Ok(targets) = eval_expr(&target, &self.context) {
+                            self.precious.extend(
+                                targets.into_iter().map(|t| (file.name.to_owned(), line, t)),
+                            );
+                        }
+                    }
+                }
+                _ => continue,
+            }
+            // Functions can still fail, with the wrong number of arguments say
+            let name = statement.clone().into_inner().next().unwrap().as_str();
+            let is_vardecl = statement.as_rule() == Rule::vardecl;
+            if let Err(e) =
+                parse_statement(statement, &mut self.context, &mut self.taskfile, file.name)
+            {
+                let message = match e {
+                    SuruError::Eval { message, .. } => message,
+                    e => e.to_string(),
+                };
+                self.problem(file.name, line, message);
+                if is_vardecl {
+                    self.context.insert(name.to_owned(), Vec::new());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn declare(&mut self, file: &Source, name: &str, line: usize) {
+        self.declared
+            .entry(name.to_owned())
+            .or_insert_with(|| (file.name.to_owned(), line));
+        if file.kind == Kind::Prologue {
+            return;
+        }
+        let unused = Unused {
+            file: file.name.to_owned(),
+            line,
+            used_before: self.used.contains(name),
+        };
+        if let Some(previous) = self.unused.insert(name.to_owned(), unused) {
+            let message = format!(
+                "{} is never used before it is declared again on line {}",
+                name, line
+            );
+            self.problem(&previous.file, previous.line, message);
+        }
+    }
+
+    /// Profiles are applied before anything else is read, so they can only use
+    /// variables set on the command line or earlier in the profile.
+    fn profile(&mut self, file: &Source, profile: Pair<Rule>) {
+        let mut declared = HashSet::new();
+        for vardecl in profile.into_inner().skip(1) {
+            let mut refs = Vec::new();
+            references(vardecl.clone(), &mut refs);
+            for (name, pair) in refs {
+                if self.initial.get(name).is_none() && !declared.contains(name) {
+                    let message = format!(
+                        "{} is not defined, profiles can only use variables set on the \
+                         command line or earlier in the profile",
+                        name
+                    );
+                    self.problem(file.name, file.line(&pair), message);
+                }
+            }
+            let name = vardecl.clone().into_inner().next().unwrap().as_str();
+            declared.insert(name);
+            self.profile_vars
+                .push((name.to_owned(), file.name.to_owned(), file.line(&vardecl)));
+        }
+    }
+
+    fn variables(&mut self) {
+        for (name, file, line) in std::mem::take(&mut self.undeclared) {
+            let message = match self.declared.get(&name) {
+                Some((f, l)) if *f == file => {
+                    format!("{} is used before it is declared on line {}", name, l)
+                }
+                Some((f, l)) => format!("{} is used before it is declared in {}:{}", name, f, l),
+                None => format!("{} is not defined", name),
+            };
+            self.problem(&file, line, message);
+        }
+
+        let mut unused: Vec<_> = std::mem::take(&mut self.unused).into_iter().collect();
+        unused.sort_by_key(|(_, u)| u.line);
+        for (name, u) in unused {
+            let message = if u.used_before {
+                format!(
+                    "{} is never used after it is declared, variables are expanded where \
+                     they are used so this has no effect on earlier uses",
+                    name
+                )
+            } else {
+                format!("{} is declared but never used", name)
+            };
+            self.problem(&u.file, u.line, message);
+        }
+
+        for (name, file, line) in std::mem::take(&mut self.profile_vars) {
+            if !self.used.contains(&name) {
+                self.problem(&file, line, format!("{} is declared but never used", name));
+            }
+        }
+    }
+
+    fn graph(&mut self, sourcedir: &Path, builddir: &Path) {
+        let recipes = std::mem::take(&mut self.taskfile.recipes);
+        let declarations = std::mem::take(&mut self.declarations);
+        let name = |file: &str| decannonicalize(file.to_owned(), sourcedir, builddir);
+
+        let mut targets: HashMap<String, Vec<&Declaration>> = HashMap::new();
+        for declaration in &declarations {
+            let target_name = name(&declaration.target);
+            let target = targets.entry(target_name.clone()).or_default();
+            self.duplicate(&target_name, declaration, target);
+            target.push(declaration);
+        }
+
+        let mut implicit: Vec<(String, Vec<String>, &Declaration)> = Vec::new();
+        for declaration in &declarations {
+            for input in declaration.inputs.iter().map(|i| name(i)) {
+                if targets.contains_key(&input)
+                    || sourcedir.join(&input).exists()
+                    || implicit.iter().any(|(i, ..)| *i == input)
+                {
+                    continue;
+                }
+                let Some(candidates) = recipes.get(remove_prefix(&input)) else {
+                    // Headers that no longer exist show up in depfiles until the
+                    // next build, which is fine
+                    if declaration.kind == Kind::Sufile {
+                        let message = format!(
+                            "{} is not a source, a target, or buildable by any recipe",
+                            input
+                        );
+                        self.problem(&declaration.file, declaration.line, message);
+                    }
+                    continue;
+                };
+                match determine_deps(&input, candidates, sourcedir) {
+                    Ok((inputs, _)) => implicit.push((input, inputs, declaration)),
+                    Err(found) if found.is_empty() => {
+                        let message = format!(
+                            "No recipe can build {}, none of them have their inputs: {}",
+                            input,
+                            self.describe(&input, candidates.iter(), candidates)
+                        );
+                        self.problem(&declaration.file, declaration.line, message);
+                    }
+                    Err(found) => {
+                        let message = format!(
+                            "More than one recipe can build {}: {}",
+                            input,
+                            self.describe(&input, found.into_iter(), candidates)
+                        );
+                        self.problem(&declaration.file, declaration.line, message);
+                    }
+                }
+            }
+        }
+
+        for (file, line, target) in std::mem::take(&mut self.precious) {
+            let target = name(&target);
+            if !targets.contains_key(&target) && !implicit.iter().any(|(i, ..)| *i == target) {
+                let message = format!("{} is precious, but it isn't a target", target);
+                self.problem(&file, line, message);
+            }
+        }
+
+        let mut used = HashSet::new();
+        let mut names: Vec<_> = targets.keys().cloned().collect();
+        names.sort();
+        for target in names {
+            let declared = &targets[&target];
+            let mut inputs: Vec<String> = Vec::new();
+            for input in declared.iter().flat_map(|d| &d.inputs).map(|i| name(i)) {
+                if !inputs.contains(&input) {
+                    inputs.push(input);
+                }
+            }
+            match recipe_for(&target, &inputs, &recipes) {
+                Some(recipe) => {
+                    used.insert(recipe as *const Recipe);
+                }
+                None => {
+                    let first = declared[0];
+                    let message = format!("No recipe builds {} from its inputs", target);
+                    self.problem(&first.file, first.line, message);
+                }
+            }
+        }
+        for (target, inputs, _) in &implicit {
+            if let Some(recipe) = recipe_for(target, inputs, &recipes) {
+                used.insert(recipe as *const Recipe);
+            }
+        }
+
+        let mut declared: Vec<_> = self.recipes.iter().collect();
+        declared.sort_by_key(|(_, (_, _, line))| *line);
+        let mut problems = Vec::new();
+        for ((key, index), (file, kind, line)) in declared {
+            let recipe = &recipes[key][*index];
+            if *kind != Kind::Sufile || used.contains(&(recipe as *const Recipe)) {
+                continue;
+            }
+            let shadowed_by = recipes[key][..*index].iter().position(|earlier| {
+                recipe.templ_in.iter().all(|e| earlier.templ_in.contains(e))
+                    && recipe.any_in.iter().all(|e| earlier.any_in.contains(e))
+            });
+            let message = match shadowed_by {
+                Some(earlier) => {
+                    let (f, _, l) = &self.recipes[&(key.clone(), earlier)];
+                    format!(
+                        "The recipe {} can never be used, the recipe at {}:{} comes first \
+                         and matches the same inputs",
+                        recipe.describe(key),
+                        f,
+                        l
+                    )
+                }
+                None => format!(
+                    "The recipe {} isn't used to build anything",
+                    recipe.describe(key)
+                ),
+            };
+            problems.push(Problem {
+                file: file.clone(),
+                line: *line,
+                message,
+            });
+        }
+        self.problems.extend(problems);
+    }
+
+    /// Reports a task declared in more than one file of the same kind. A
+    /// target declared by the sufile and by a depfile is expected, since
+    /// that's how depfiles add headers, and so is one declared on several
+    /// lines of the same file, which are merged.
+    fn duplicate(&mut self, target: &str, declaration: &Declaration, earlier: &[&Declaration]) {
+        let Some(first) = earlier
+            .iter()
+            .find(|d| d.kind == declaration.kind && d.file != declaration.file)
+        else {
+            return;
+        };
+        let message = format!(
+            "{} is already declared in {}:{}",
+            target, first.file, first.line
+        );
+        self.problem(&declaration.file, declaration.line, message);
+    }
+
+    /// Lists recipes along with where they were declared.
+    fn describe<'a>(
+        &self,
+        target: &str,
+        recipes: impl Iterator<Item = &'a Recipe>,
+        candidates: &[Recipe],
+    ) -> String {
+        let key = remove_prefix(target);
+        recipes
+            .map(|recipe| {
+                let index = candidates
+                    .iter()
+                    .position(|c| std::ptr::eq(c, recipe))
+                    .unwrap();
+                match self.recipes.get(&(key.to_owned(), index)) {
+                    Some((file, _, line)) => {
+                        format!("{} ({}:{})", recipe.describe(key), file, line)
+                    }
+                    None => recipe.describe(key),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::parser::join_lines;
+    use crate::util::TempDir;
+
+    use super::*;
+
+    fn messages(problems: &[Problem]) -> Vec<String> {
+        problems.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let input = "A = 1 \\\n  2\n\nB = \\\r\n 3 \\\\x\nC = $(A)\n";
+        let source = Source::new("test", input, Kind::Sufile);
+        assert_eq!(source.text, join_lines(input) + "\n");
+        let lines: Vec<_> = statements(&source.text, "test")
+            .unwrap()
+            .filter(|s| s.as_rule() != Rule::EOI)
+            .map(|s| source.line(&s))
+            .collect();
+        assert_eq!(lines, [1, 4, 6]);
+    }
+
+    #[test]
+    fn test_check() {
+        let temp = TempDir::new("check");
+        let dir = temp.path();
+        for source in ["main.c", "lib.c", "lib.cc", "gen.in"] {
+            fs::write(dir.join(source), "").unwrap();
+        }
+
+        let sufile = "UNUSED = 1\n\
+                      CFLAGS = -O0\n\
+                      X = $(LATER)\n\
+                      LATER = 2\n\
+                      profile debug { DEBUG = -g }\n\
+                      a: main.o lib.o missing.txt \\\n    \
+                      gen.h\n\
+                      a: main.o\n\
+                      %.o < %.cc\n\
+                      \tg++ -c $^ -o $@\n\
+                      %.o < %.c\n\
+                      \tcc -c $^ -o $@\n\
+                      %.so < *.o\n\
+                      \tcc -shared $^ -o $@ $(X)\n\
+                      % < *.o\n\
+                      \tcc $^ -o $@\n\
+                      %.h < %.h.in\n\
+                      \tgen $^ $@\n\
+                      precious a main.o nope\n";
+        let depfile = format!("{}: {}\n", dir.join("main.o").display(), "main.c gone.hpp");
+        let depfiles = [
+            ("main.d".to_owned(), depfile.clone()),
+            ("old/main.d".to_owned(), depfile),
+        ];
+        let problems = check("tasks.su", sufile, &depfiles, Context::default(), dir, dir).unwrap();
+        assert_eq!(
+            messages(&problems),
+            [
+                "old/main.d:1: main.o is already declared in main.d:1".to_owned(),
+                "tasks.su:1: UNUSED is declared but never used".to_owned(),
+                "tasks.su:2: CFLAGS is never used after it is declared, variables are expanded \
+                 where they are used so this has no effect on earlier uses"
+                    .to_owned(),
+                "tasks.su:3: LATER is used before it is declared on line 4".to_owned(),
+                "tasks.su:4: LATER is never used after it is declared, variables are expanded \
+                 where they are used so this has no effect on earlier uses"
+                    .to_owned(),
+                "tasks.su:5: DEBUG is declared but never used".to_owned(),
+                "tasks.su:6: More than one recipe can build lib.o: %.o < %.c (prologue:7), \
+                 %.o < %.cc (tasks.su:9), %.o < %.c (tasks.su:11)"
+                    .to_owned(),
+                "tasks.su:6: missing.txt is not a source, a target, or buildable by any recipe"
+                    .to_owned(),
+                "tasks.su:6: No recipe can build gen.h, none of them have their inputs: \
+                 %.h < %.h.in (tasks.su:17)"
+                    .to_owned(),
+                "tasks.su:9: The recipe %.o < %.cc isn't used to build anything".to_owned(),
+                "tasks.su:11: The recipe %.o < %.c can never be used, the recipe at prologue:7 \
+                 comes first and matches the same inputs"
+                    .to_owned(),
+                "tasks.su:13: The recipe %.so < *.o isn't used to build anything".to_owned(),
+                "tasks.su:17: The recipe %.h < %.h.in isn't used to build anything".to_owned(),
+                "tasks.su:19: nope is precious, but it isn't a target".to_owned(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::{fmt, io};
+
+/// A command that failed while building a target.
+#[derive(Debug)]
+pub struct Failure {
+    pub target: String,
+    pub command: String,
+    /// The exit status and stderr of the command, or why it couldn't be run.
+    pub output: String,
+}
+
+/// Everything that can stop suru from reading a sufile or finishing a build.
+#[derive(Debug)]
+pub enum SuruError {
+    /// A sufile or depfile isn't valid syntax.
+    Parse { file: String, message: String },
+    /// A variable or function couldn't be evaluated. The location is filled in
+    /// once the statement it is in is known.
+    Eval {
+        location: Option<(String, usize)>,
+        message: String,
+    },
+    /// The tasks and recipes don't describe something that can be built.
+    Graph(String),
+    /// The command line asks for something that doesn't exist, such as a
+    /// profile or a target.
+    Usage(String),
+    /// A file couldn't be read or written.
+    Io { context: String, source: io::Error },
+    /// Commands failed while building.
+    Command(Vec<Failure>),
+    /// SIGINT or SIGTERM stopped the build.
+    Interrupted,
+    /// A bug in suru.
+    Internal(String),
+}
+
+pub type Result<T, E = SuruError> = std::result::Result<T, E>;
+
+impl SuruError {
+    /// The exit code for the error: 1 if the build failed, 2 if the sufile or
+    /// the command line is wrong, 130 if suru was interrupted, and 3 for
+    /// anything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SuruError::Command(_) => 1,
+            SuruError::Parse { .. }
+            | SuruError::Eval { .. }
+            | SuruError::Graph(_)
+            | SuruError::Usage(_) => 2,
+            SuruError::Io { .. } | SuruError::Internal(_) => 3,
+            SuruError::Interrupted => 130,
+        }
+    }
+
+    pub(crate) fn eval(message: String) -> Self {
+        SuruError::Eval {
+            location: None,
+            message,
+        }
+    }
+
+    /// Records where an evaluation error happened, unless it already says.
+    pub(crate) fn at(self, file: &str, line: usize) -> Self {
+        match self {
+            SuruError::Eval {
+                location: None,
+                message,
+            } => SuruError::Eval {
+                location: Some((file.to_owned(), line)),
+                message,
+            },
+            e => e,
+        }
+    }
+
+    /// For `map_err`, describing what was being done when an IO error happened.
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| SuruError::Io { context, source }
+    }
+}
+
+impl fmt::Display for SuruError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuruError::Parse { file, message } => {
+                write!(f, "Unable to parse {}:\n{}", file, message)
+            }
+            SuruError::Eval {
+                location: Some((file, line)),
+                message,
+            } => write!(f, "{}:{}: {}", file, line, message),
+            SuruError::Eval {
+                location: None,
+                message,
+            } => write!(f, "{}", message),
+            SuruError::Graph(message) | SuruError::Usage(message) => write!(f, "{}", message),
+            SuruError::Io { context, source } => write!(f, "{}: {}", context, source),
+            SuruError::Command(failures) => {
+                write!(f, "{} target(s) failed to build", failures.len())
+            }
+            SuruError::Interrupted => write!(f, "Interrupted"),
+            SuruError::Internal(message) => {
+                write!(f, "Internal error, this is a bug in suru: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SuruError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SuruError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Something that happened during a build, written as one line of JSON for
+/// `--message-format json`. Durations are in seconds.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The build graph was loaded, and building is about to start.
+    GraphLoaded {
+        targets: usize,
+    },
+    /// A target is out of date, and its first command is about to run.
+    TargetStarted {
+        target: String,
+        reason: String,
+    },
+    /// A command is about to run.
+    CommandRun {
+        target: String,
+        command: String,
+    },
+    /// A target didn't need to be built.
+    UpToDate {
+        target: String,
+    },
+    TargetFinished {
+        target: String,
+        duration: f64,
+    },
+    /// A command failed. `status` is missing if it was killed by a signal or
+    /// couldn't be run, in which case `stderr` says why.
+    TargetFailed {
+        target: String,
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+    /// A target wasn't built because `dependency`, which it depends on,
+    /// failed.
+    Skipped {
+        target: String,
+        dependency: String,
+    },
+    BuildFinished {
+        success: bool,
+        failed: usize,
+        duration: f64,
+    },
+}
+
+/// Receives events as they happen. It is called from the threads running
+/// commands, so it shouldn't block for long.
+pub trait Listener: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> Listener for F {
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Writes each event to stdout as a line of JSON.
+pub struct JsonLines;
+
+impl Listener for JsonLines {
+    fn event(&self, event: &Event) {
+        let line = serde_json::to_string(event).expect("Events are always valid JSON");
+        // Locked so that lines from different threads don't interleave
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
+
+/// Passes events to a listener, timing the build.
+pub struct Events {
+    start: Instant,
+    listener: Arc<dyn Listener>,
+}
+
+impl Events {
+    pub fn new(listener: Arc<dyn Listener>) -> Self {
+        Self {
+            start: Instant::now(),
+            listener,
+        }
+    }
+
+    /// The time since the build started.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn emit(&self, event: Event) {
+        self.listener.event(&event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let event = Event::TargetFailed {
+            target: "main.o".into(),
+            command: "gcc -c main.c".into(),
+            status: Some(1),
+            stderr: "main.c:1: error\n".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"target_failed","target":"main.o","command":"gcc -c main.c","status":1,"stderr":"main.c:1: error\n"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Event::GraphLoaded { targets: 3 }).unwrap(),
+            r#"{"event":"graph_loaded","targets":3}"#
+        );
+    }
+}
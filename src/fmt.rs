@@ -0,0 +1,298 @@
+use pest::iterators::Pair;
+
+use crate::error::Result;
+use crate::parser::{join_lines, statements, Rule};
+
+/// Lines longer than this are wrapped with `\`.
+const WIDTH: usize = 100;
+const TASK_INDENT: &str = "    ";
+/// Continued recipe steps can't be indented with tabs, since they would end up
+/// in the middle of the step once the lines are joined.
+const STEP_INDENT: &str = "        ";
+
+/// A line of the formatted file, before variable declarations are aligned.
+#[derive(Debug)]
+enum Line {
+    Var {
+        name: String,
+        value: Vec<String>,
+        comment: Option<String>,
+    },
+    Other {
+        text: String,
+        comment: Option<String>,
+    },
+    Comment(String),
+    Blank,
+}
+
+impl Line {
+    /// Adds a comment to the end of the line. Sufiles don't allow spaces before
+    /// a comment at the end of a line, so none are added.
+    fn attach(&mut self, trailing: &str) {
+        match self {
+            Line::Var { comment, .. } | Line::Other { comment, .. } => {
+                comment.get_or_insert_with(String::new).push_str(trailing)
+            }
+            Line::Comment(c) => c.push_str(trailing),
+            Line::Blank => unreachable!("Blank lines are only added before something"),
+        }
+    }
+}
+
+/// Rewrites a sufile in canonical form: one space between words, aligned `=`
+/// in runs of variable declarations, a single tab before recipe steps, long
+/// lines wrapped, and at most one blank line in a row. Comments between
+/// statements are kept, and statements containing comments are left as is.
+///
+/// Inputs aren't sorted, since their order decides the order of `$^`.
+pub fn format(input: &str, filename: &str) -> Result<String> {
+    let input = join_lines(input) + "\n";
+    let mut lines = Vec::new();
+    let mut end = 0;
+    for statement in statements(&input, filename)? {
+        if statement.as_rule() == Rule::EOI {
+            break;
+        }
+        let span = statement.as_span();
+        gap(&input[end..span.start()], &mut lines, true);
+        end = span.end();
+
+        // Comments at the end of the line end up in the statement
+        let body_end = match statement.as_rule() {
+            Rule::profile => span.start() + statement.as_str().rfind('}').unwrap() + 1,
+            rule => {
+                let atoms = statement
+                    .clone()
+                    .into_inner()
+                    .flatten()
+                    .filter(|p| {
+                        matches!(
+                            p.as_rule(),
+                            Rule::expr | Rule::template | Rule::implicit_var
+                        )
+                    })
+                    .map(|p| p.as_span().end());
+                // A task without inputs ends at its colon
+                let colon = (rule == Rule::task).then(|| {
+                    let target = statement.clone().into_inner().next().unwrap();
+                    let after = target.as_span().end();
+                    after + input[after..].find(':').unwrap() + 1
+                });
+                atoms.chain(colon).max().unwrap_or(span.end())
+            }
+        };
+        let body = &input[span.start()..body_end];
+        if body.contains("/*") {
+            lines.push(Line::Other {
+                text: body.to_owned(),
+                comment: None,
+            });
+        } else {
+            lines.push(format_statement(statement));
+        }
+        let trailing = input[body_end..span.end()].trim();
+        if !trailing.is_empty() {
+            lines.last_mut().unwrap().attach(trailing);
+        }
+    }
+    gap(&input[end..], &mut lines, false);
+    Ok(render(&lines, ""))
+}
+
+/// Adds the comments and blank lines between two statements.
+fn gap(text: &str, lines: &mut Vec<Line>, before_statement: bool) {
+    let mut newlines = 0;
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        newlines += rest[..start].matches('\n').count();
+        let len = rest[start..]
+            .find("*/")
+            .map_or(rest.len() - start, |e| e + 2);
+        let comment = &rest[start..start + len];
+        match lines.last_mut() {
+            Some(last) if newlines == 0 => last.attach(comment),
+            _ => {
+                if newlines >= 2 && !lines.is_empty() {
+                    lines.push(Line::Blank);
+                }
+                lines.push(Line::Comment(comment.to_owned()));
+            }
+        }
+        newlines = 0;
+        rest = &rest[start + len..];
+    }
+    newlines += rest.matches('\n').count();
+    if before_statement && newlines >= 2 && !lines.is_empty() {
+        lines.push(Line::Blank);
+    }
+}
+
+fn format_statement(statement: Pair<Rule>) -> Line {
+    let rule = statement.as_rule();
+    let mut inner = statement.into_inner();
+    match rule {
+        Rule::vardecl => Line::Var {
+            name: inner.next().unwrap().as_str().to_owned(),
+            value: inner.map(|p| p.as_str().to_owned()).collect(),
+            comment: None,
+        },
+        Rule::task => {
+            let target = inner.next().unwrap().as_str();
+            let inputs: Vec<_> = inner.map(|p| p.as_str()).collect();
+            Line::Other {
+                text: wrap(format!("{}:", target), &inputs, TASK_INDENT),
+                comment: None,
+            }
+        }
+        Rule::precious => {
+            let targets: Vec<_> = inner.map(|p| p.as_str()).collect();
+            Line::Other {
+                text: wrap("precious".to_owned(), &targets, TASK_INDENT),
+                comment: None,
+            }
+        }
+        Rule::recipe => {
+            let target = inner.next().unwrap().as_str();
+            let mut inputs = Vec::new();
+            let mut steps = Vec::new();
+            for pair in inner {
+                match pair.as_rule() {
+                    Rule::template => inputs.push(pair.as_str()),
+                    _ => {
+                        let words: Vec<_> = pair.into_inner().map(|p| p.as_str()).collect();
+                        steps.push(wrap("\t".to_owned(), &words, STEP_INDENT));
+                    }
+                }
+            }
+            let mut text = wrap(format!("{} <", target), &inputs, TASK_INDENT);
+            for step in steps {
+                text.push('\n');
+                text.push_str(&step);
+            }
+            Line::Other {
+                text,
+                comment: None,
+            }
+        }
+        Rule::profile => {
+            let name = inner.next().unwrap().as_str();
+            let vars: Vec<_> = inner.map(format_statement).collect();
+            let mut text = format!("profile {} {{\n", name);
+            text.push_str(&render(&vars, TASK_INDENT));
+            text.push('}');
+            Line::Other {
+                text,
+                comment: None,
+            }
+        }
+        unknown => unreachable!("This should never occur {:?}", unknown),
+    }
+}
+
+/// Joins `words` onto `head` with spaces, continuing onto a new line indented
+/// by `indent` whenever a line would get too long.
+fn wrap(head: String, words: &[&str], indent: &str) -> String {
+    let mut out = head;
+    let mut line_start = 0;
+    let mut first = true;
+    for word in words {
+        let len = out.len() - line_start + out[line_start..].matches('\t').count() * 7;
+        if len + 1 + word.len() > WIDTH - 2 && !first {
+            out.push_str(" \\\n");
+            line_start = out.len();
+            out.push_str(indent);
+        } else if !out.ends_with('\t') {
+            out.push(' ');
+        }
+        out.push_str(word);
+        first = false;
+    }
+    out
+}
+
+fn render(lines: &[Line], indent: &str) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        // Aligns the `=` of each run of declarations
+        let run = lines[i..]
+            .iter()
+            .take_while(|l| matches!(l, Line::Var { .. }))
+            .count();
+        let width = lines[i..i + run]
+            .iter()
+            .map(|l| match l {
+                Line::Var { name, .. } => name.len(),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0);
+
+        for line in &lines[i..i + run.max(1)] {
+            let (text, comment) = match line {
+                Line::Var {
+                    name,
+                    value,
+                    comment,
+                } => {
+                    let head = format!("{}{:width$} =", indent, name, width = width);
+                    let value: Vec<_> = value.iter().map(String::as_str).collect();
+                    let continued = format!("{}{}", indent, TASK_INDENT);
+                    (wrap(head, &value, &continued), comment)
+                }
+                Line::Other { text, comment } => (text.clone(), comment),
+                Line::Comment(c) => (format!("{}{}", indent, c), &None),
+                Line::Blank => (String::new(), &None),
+            };
+            out.push_str(&text);
+            if let Some(comment) = comment {
+                out.push_str(comment);
+            }
+            out.push('\n');
+        }
+        i += run.max(1);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let input =
+            "\n\n/* Flags */\nCFLAGS   =    -O3 -g\nLINKFLAGS = $(CFLAGS)/* for linking */\n\n\n\
+                     a:main.o \\\n  lib/lib.o\n%.o <   %.c\n\t  gcc  -c $^ -o $@\n\
+                     profile debug {  X = 1\n  LONGER = 2 }\nprecious   a  lib/lib.o\n";
+        let expected = "/* Flags */\n\
+                        CFLAGS    = -O3 -g\n\
+                        LINKFLAGS = $(CFLAGS)/* for linking */\n\
+                        \n\
+                        a: main.o lib/lib.o\n\
+                        %.o < %.c\n\
+                        \tgcc -c $^ -o $@\n\
+                        profile debug {\n    X      = 1\n    LONGER = 2\n}\n\
+                        precious a lib/lib.o\n";
+        assert_eq!(format(input, "test").unwrap(), expected);
+        assert_eq!(format(expected, "test").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_wrap() {
+        let inputs: Vec<_> = (0..30).map(|i| format!("file{}.o", i)).collect();
+        let input = format!("a: {}\n", inputs.join(" "));
+        let formatted = format(&input, "test").unwrap();
+        assert!(formatted.lines().all(|l| l.len() <= WIDTH));
+        assert_eq!(formatted.lines().nth(1).unwrap(), "    file11.o file12.o file13.o file14.o file15.o file16.o file17.o file18.o file19.o file20.o \\");
+        assert_eq!(join_lines(&formatted).split_whitespace().count(), 31);
+        assert_eq!(format(&formatted, "test").unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_keeps_comments_in_statements() {
+        let input = "a: main.o /* lib.o */ other.o\nb:/* nothing */\n";
+        assert_eq!(format(input, "test").unwrap(), input);
+    }
+}
@@ -1,60 +1,66 @@
-use std::{env, path::PathBuf, str::FromStr};
+use std::{env, path::PathBuf};
 
-pub fn eval_function(name: &str, inputs: &[String]) -> Vec<String> {
+use crate::error::{Result, SuruError};
+
+pub fn eval_function(name: &str, inputs: &[String]) -> Result<Vec<String>> {
     match name {
-        "upper" => inputs.iter().map(|s| s.to_uppercase()).collect(),
+        "upper" => Ok(inputs.iter().map(|s| s.to_uppercase()).collect()),
         "exe" => exe(inputs),
         "env" => env(inputs),
-        "or" => or(inputs),
+        "or" => Ok(or(inputs)),
         "path" => path(inputs),
-        "just" => inputs.to_owned(),
+        "just" => Ok(inputs.to_owned()),
         "first" => first(inputs),
         "last" => last(inputs),
-        _ => panic!("Unknown function"),
+        _ => Err(SuruError::eval(format!("Unknown function {}", name))),
     }
 }
 
-fn exe(inputs: &[String]) -> Vec<String> {
-    if inputs.len() != 1 {
-        "Expected exactly one argument to the function exe";
+fn exe(inputs: &[String]) -> Result<Vec<String>> {
+    if inputs.is_empty() {
+        return Err(SuruError::eval(
+            "Expected an argument to the function exe".to_owned(),
+        ));
     }
     let mut a = inputs[0].clone();
     if cfg!(windows) {
         a.push_str(".exe");
     }
-    vec![a]
+    Ok(vec![a])
 }
 
-fn env(inputs: &[String]) -> Vec<String> {
-    if inputs.len() != 1 {
-        "Expected exactly one argument to the function env";
+fn env(inputs: &[String]) -> Result<Vec<String>> {
+    if inputs.is_empty() {
+        return Err(SuruError::eval(
+            "Expected an argument to the function env".to_owned(),
+        ));
     }
     let var = std::env::var(&inputs[0]);
     match var {
-        Ok(val) => vec![val],
+        Ok(val) => Ok(vec![val]),
         Err(err) => match err {
-            std::env::VarError::NotPresent => Vec::new(),
-            std::env::VarError::NotUnicode(_) => {
-                panic!("Environment variable contained non-unicode data, contact developer")
-            }
+            std::env::VarError::NotPresent => Ok(Vec::new()),
+            std::env::VarError::NotUnicode(_) => Err(SuruError::eval(format!(
+                "The environment variable {} isn't valid unicode",
+                inputs[0]
+            ))),
         },
     }
 }
 
-fn path(inputs: &[String]) -> Vec<String> {
-    if inputs.len() != 1 {
-        "Expected exactly one argument to the function exists";
+fn path(inputs: &[String]) -> Result<Vec<String>> {
+    if inputs.is_empty() {
+        return Err(SuruError::eval(
+            "Expected an argument to the function path".to_owned(),
+        ));
     }
     let mut input = inputs[0].clone();
     if cfg!(windows) && !input.ends_with(".exe") {
         input.push_str(".exe");
     }
-    let path = PathBuf::from_str(&input).expect(&format!(
-        "Unable to parse executable {} when evaluating path",
-        input
-    ));
+    let path = PathBuf::from(&input);
 
-    env::var_os("PATH")
+    Ok(env::var_os("PATH")
         .and_then(|paths| {
             env::split_paths(&paths)
                 .filter_map(|dir| {
@@ -66,39 +72,48 @@ fn path(inputs: &[String]) -> Vec<String> {
                 })
                 .next()
         })
-        .unwrap_or_default()
+        .unwrap_or_default())
 }
 
 fn or(inputs: &[String]) -> Vec<String> {
-    inputs.get(0).map(|s| vec![s.clone()]).unwrap_or_default()
+    inputs.first().map(|s| vec![s.clone()]).unwrap_or_default()
 }
 
-fn first(inputs: &[String]) -> Vec<String> {
-    if inputs.len() < 1 {
-        panic!("Expected at least one argument to function first");
-    }
-    let num: usize = inputs[0]
-        .parse()
-        .expect(&format!("Parsing error when parsing {}", &inputs[0]));
-    if inputs.len() < num + 1 {
-        panic!("Expected at least {} arguments for function first", num + 1);
-    }
+fn first(inputs: &[String]) -> Result<Vec<String>> {
+    let num = count(inputs, "first")?;
 
-    inputs[1..num+1].to_owned()
+    Ok(inputs[1..num + 1].to_owned())
 }
 
-fn last(inputs: &[String]) -> Vec<String> {
-    if inputs.len() == 0 {
-        panic!("Expected at least one argument to function last");
-    }
-    let num: usize = inputs[0]
-        .parse()
-        .expect(&format!("Parsing error when parsing {}", &inputs[0]));
+fn last(inputs: &[String]) -> Result<Vec<String>> {
+    let num = count(inputs, "last")?;
+
+    Ok(inputs[(inputs.len() - num)..inputs.len()].to_owned())
+}
+
+/// Reads the number of words `first` and `last` should keep, and checks that
+/// there are that many.
+fn count(inputs: &[String], function: &str) -> Result<usize> {
+    let Some(num) = inputs.first() else {
+        return Err(SuruError::eval(format!(
+            "Expected at least one argument to function {}",
+            function
+        )));
+    };
+    let num: usize = num.parse().map_err(|_| {
+        SuruError::eval(format!(
+            "Expected a number as the first argument to function {}, got {}",
+            function, num
+        ))
+    })?;
     if inputs.len() < num + 1 {
-        panic!("Expected at least {} arguments for function last", num + 1);
+        return Err(SuruError::eval(format!(
+            "Expected at least {} arguments for function {}",
+            num + 1,
+            function
+        )));
     }
-
-    inputs[(inputs.len() - num)..inputs.len()].to_owned()
+    Ok(num)
 }
 #[cfg(test)]
 mod test {
@@ -109,10 +124,21 @@ mod test {
     #[test]
     fn test_first_last() {
         let inputs = make_svec(&["3", "a", "b", "c", "d"]);
-        let results = last(&inputs);
+        let results = last(&inputs).unwrap();
         assert_eq!(results, make_svec(&["b", "c", "d"]));
 
-        let results = first(&inputs);
+        let results = first(&inputs).unwrap();
         assert_eq!(results, make_svec(&["a", "b", "c"]));
+
+        assert!(first(&make_svec(&["3", "a"])).is_err());
+        assert!(last(&make_svec(&["x", "a"])).is_err());
+        assert!(eval_function("nope", &[]).is_err());
+
+        // Extra arguments have always been ignored
+        assert_eq!(
+            eval_function("exe", &make_svec(&["a", "b"])).unwrap().len(),
+            1
+        );
+        assert!(eval_function("exe", &[]).is_err());
     }
 }
@@ -39,36 +39,27 @@ pub fn pre_compile(
     }
 
     // heuristically tell if it's a c/cpp file.
-    if deps
-        .iter()
-        .all(|d| !d.extension().unwrap().to_string_lossy().contains("c"))
-    {
+    if deps.iter().all(|d| {
+        !d.extension()
+            .is_some_and(|e| e.to_string_lossy().contains('c'))
+    }) {
         return;
     }
 
     out.push(HookResult {
         compile_cmd: CompileCommand {
-            directory: sourcedir
-                .as_os_str()
-                .to_str()
-                .expect("Invalid unicode in path")
-                .to_owned(),
+            directory: sourcedir.to_string_lossy().into_owned(),
             arguments: cmd
                 .iter()
-                .map(|s| s.to_str().expect("Invalid unicode in command").to_owned())
+                .map(|s| s.to_string_lossy().into_owned())
                 .collect(),
-            file: target
-                .as_os_str()
-                .to_str()
-                .expect("Invalid unicode in target path")
-                .to_owned(),
+            file: target.to_string_lossy().into_owned(),
         },
     });
 }
 
 pub fn post_compile(input: &SegQueue<HookResult>, workdir: &Path) {
-    let mut compiledb = Vec::new();
-    compiledb.reserve(input.len());
+    let mut compiledb = Vec::with_capacity(input.len());
     while let Some(result) = input.pop() {
         compiledb.push(result.compile_cmd);
     }
@@ -76,7 +67,6 @@ pub fn post_compile(input: &SegQueue<HookResult>, workdir: &Path) {
     match db_file {
         Err(e) => {
             error!("Unable to open compile_commands.json: {}", e);
-            return;
         }
         Ok(f) => {
             if let Err(e) = serde_json::to_writer_pretty(f, &compiledb) {
@@ -0,0 +1,134 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering::SeqCst},
+        Arc, Mutex, Weak,
+    },
+};
+
+/// The signal that interrupted suru, or 0.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// The `die` flag of each build in progress.
+static BUILDS: Mutex<Vec<Weak<AtomicBool>>> = Mutex::new(Vec::new());
+/// The process ids of the commands that are running.
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+/// Whether [`install`] has been called.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Handles SIGINT and SIGTERM on a thread of their own, so that builds can be
+/// stopped cleanly.
+///
+/// Once this is installed, every command runs in its own process group, so a
+/// Ctrl-C in the terminal only reaches suru. The signal stops every build in progress from starting
+/// new commands, and is passed on to each running command's process group. The
+/// builds then wait for their commands to exit, delete whatever the
+/// interrupted commands wrote, and fail with
+/// [`SuruError::Interrupted`](crate::error::SuruError::Interrupted). When no
+/// build is in progress, suru exits with 130 right away.
+///
+/// This has to be called before any other threads are started, since they
+/// inherit the blocked signals.
+#[cfg(unix)]
+pub fn install() -> io::Result<()> {
+    // SAFETY: the set is initialized by sigemptyset before it is used, and
+    // sigwait is only given that set.
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        let e = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        if e != 0 {
+            return Err(io::Error::from_raw_os_error(e));
+        }
+        std::thread::Builder::new()
+            .name("signals".to_owned())
+            .spawn(move || loop {
+                let mut signal = 0;
+                if libc::sigwait(&set, &mut signal) == 0 {
+                    interrupt(signal);
+                }
+            })?;
+    }
+    INSTALLED.store(true, SeqCst);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn interrupt(signal: i32) {
+    SIGNAL.store(signal, SeqCst);
+    let mut stopped = false;
+    for die in BUILDS.lock().expect("Builds poisoned").iter() {
+        if let Some(die) = die.upgrade() {
+            die.store(true, SeqCst);
+            stopped = true;
+        }
+    }
+    if !stopped {
+        std::process::exit(130);
+    }
+    for &pid in RUNNING.lock().expect("Running commands poisoned").iter() {
+        kill(pid, signal);
+    }
+}
+
+/// Sends a signal to the process group a command leads.
+#[cfg(unix)]
+fn kill(pid: u32, signal: i32) {
+    // SAFETY: kill has no memory safety requirements. The process group can
+    // only be gone if the command already exited, which is harmless.
+    unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+}
+
+/// Whether signals are handled by [`install`]. Until they are, commands stay
+/// in suru's process group, so that a Ctrl-C reaches them too, such as when
+/// suru is used as a library.
+pub fn installed() -> bool {
+    INSTALLED.load(SeqCst)
+}
+
+/// Whether suru has been interrupted.
+pub fn interrupted() -> bool {
+    SIGNAL.load(SeqCst) != 0
+}
+
+/// Sets `die` when suru is interrupted, for as long as the build it belongs to
+/// is running.
+pub fn stop_on_interrupt(die: &Arc<AtomicBool>) {
+    let mut builds = BUILDS.lock().expect("Builds poisoned");
+    builds.retain(|b| b.strong_count() > 0);
+    builds.push(Arc::downgrade(die));
+    if interrupted() {
+        die.store(true, SeqCst);
+    }
+}
+
+/// A running command, which is passed any signal that interrupts suru until
+/// this is dropped.
+pub struct Running(u32);
+
+impl Running {
+    pub fn new(pid: u32) -> Self {
+        RUNNING.lock().expect("Running commands poisoned").push(pid);
+        // The signal may have already been passed on before this started
+        #[cfg(unix)]
+        if interrupted() {
+            kill(pid, SIGNAL.load(SeqCst));
+        }
+        Self(pid)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING
+            .lock()
+            .expect("Running commands poisoned")
+            .retain(|&pid| pid != self.0);
+    }
+}
@@ -1,6 +1,23 @@
-pub mod parser;
-pub mod functions;
-pub mod util;
 pub mod build;
-pub mod once_fallible;
+pub mod builder;
+pub mod check;
+pub mod error;
+pub mod events;
+pub mod fmt;
+pub mod functions;
 pub mod hooks;
+pub mod interrupt;
+pub mod load;
+pub mod logs;
+pub mod manifest;
+pub mod parser;
+pub mod progress;
+pub mod query;
+pub mod state;
+pub mod trace;
+pub mod util;
+#[cfg(target_os = "linux")]
+pub mod watch;
+
+pub use build::BuildReport;
+pub use builder::Build;
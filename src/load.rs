@@ -0,0 +1,229 @@
+use std::{
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+use crate::error::{Result, SuruError};
+use crate::parser::{apply_profile, join_lines, parse, profiles, Context, TaskFile, PROLOGUE};
+
+/// Reads the prologue, every depfile in the build directory and the sufile,
+/// which is read from stdin if it is `-`. The profile's variables are applied
+/// before anything is parsed.
+pub fn load(
+    sufile: &Path,
+    profile: Option<&str>,
+    build_root: &Path,
+    mut context: Context,
+) -> Result<TaskFile> {
+    let (tasks, filename) = read_sufile(sufile)?;
+    let tasks = preprocess(&tasks);
+    if let Some(profile) = profile {
+        select_profile(&tasks, profile, &mut context, &filename)?;
+    }
+
+    let mut buildstate = Default::default();
+
+    parse(PROLOGUE, &mut context, &mut buildstate, "prologue")?;
+
+    for (name, contents) in read_depfiles(build_root)? {
+        parse(&preprocess(&contents), &mut context, &mut buildstate, &name)?;
+    }
+
+    parse(&tasks, &mut context, &mut buildstate, &filename)?;
+    Ok(buildstate)
+}
+
+/// Reads the sufile, or stdin if it is `-`, along with the name to use for it
+/// in messages.
+pub fn read_sufile(sufile: &Path) -> Result<(String, String)> {
+    if sufile == Path::new("-") {
+        let mut tasks = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut tasks)
+            .map_err(SuruError::io("Unable to read the sufile from stdin"))?;
+        let name = "<stdin>".to_owned();
+        Ok((utf8(tasks, &name)?, name))
+    } else {
+        let tasks = std::fs::read(sufile).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => SuruError::Usage(format!("{} doesn't exist", sufile.display())),
+            _ => SuruError::io(format!("Unable to read {}", sufile.display()))(e),
+        })?;
+        // Shorter names for error messages and `suru query`
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let name = sufile.strip_prefix(cwd).unwrap_or(sufile);
+        let name = name.to_string_lossy().into_owned();
+        Ok((utf8(tasks, &name)?, name))
+    }
+}
+
+/// Reads every depfile in the build directory, named relative to it.
+pub fn read_depfiles(build_root: &Path) -> Result<Vec<(String, String)>> {
+    let mut depfiles = Vec::new();
+    search_dependencies(build_root, &mut depfiles)?;
+    depfiles.sort();
+
+    let mut contents = Vec::new();
+    for depfile in depfiles {
+        match std::fs::read(&depfile) {
+            Err(e) => {
+                error!(
+                    "Unable to read depfile {:?} due to: {}",
+                    depfile.file_name(),
+                    e
+                );
+            }
+            Ok(tasks) => {
+                let name = depfile
+                    .strip_prefix(build_root)
+                    .unwrap_or(&depfile)
+                    .to_string_lossy()
+                    .into_owned();
+                let tasks = utf8(tasks, &name)?;
+                contents.push((name, tasks));
+            }
+        }
+    }
+    Ok(contents)
+}
+
+/// Applies a profile from the sufile, failing if there is no such profile.
+pub fn select_profile(
+    tasks: &str,
+    profile: &str,
+    context: &mut Context,
+    filename: &str,
+) -> Result<()> {
+    if !apply_profile(tasks, profile, context, filename)? {
+        return Err(SuruError::Usage(format!(
+            "No profile named {} in {}, the profiles are: {}",
+            profile,
+            filename,
+            profiles(tasks, filename)?.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Finds `tasks.su` in `search_root` or the closest of its parents.
+pub fn find_file(search_root: &Path) -> Result<PathBuf> {
+    for parent in search_root.ancestors() {
+        match parent.read_dir() {
+            Ok(d) => {
+                for file in d {
+                    match file {
+                        Ok(f) => {
+                            if f.file_name() == "tasks.su" {
+                                return Ok(f.path());
+                            }
+                        }
+                        Err(e) => {
+                            error!("Unable to read file {} due to {}", parent.display(), e)
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                // Don't actually panic because this usually isn't a fatal error
+                error!(
+                    "Unable to search directory {} for build files due to {}",
+                    parent.display(),
+                    e
+                )
+            }
+        }
+    }
+    Err(SuruError::Usage(format!(
+        "Unable to find tasks.su in {} or any of its parents",
+        search_root.display()
+    )))
+}
+
+/// Adds every depfile in `search_root` and its subdirectories to `out`.
+/// Subdirectories that are build directories of their own, such as those of
+/// profiles, are skipped, since their depfiles describe another build.
+pub fn search_dependencies(search_root: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match search_root.read_dir() {
+        Ok(entries) => entries,
+        // Nothing has been built yet
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(SuruError::io(format!(
+                "Unable to search {} for depfiles",
+                search_root.display()
+            ))(e))
+        }
+    };
+    for file in entries.filter_map(|d| d.ok()) {
+        if file.file_type().is_ok_and(|t| t.is_dir()) {
+            if file.file_name() != ".suru" && !file.path().join(".suru").is_dir() {
+                search_dependencies(&file.path(), out)?;
+            }
+        } else if file.file_name().to_str().is_some_and(|f| f.ends_with(".d")) {
+            out.push(file.path());
+        }
+    }
+    Ok(())
+}
+
+fn utf8(file: Vec<u8>, name: &str) -> Result<String> {
+    String::from_utf8(file).map_err(|_| SuruError::Parse {
+        file: name.to_owned(),
+        message: "It isn't valid UTF-8".to_owned(),
+    })
+}
+
+/// Joins continued lines, so that the text can be parsed.
+pub fn preprocess(file: &str) -> String {
+    join_lines(file) + "\n"
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_profile_then_default() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = TempDir::new("profiles");
+        let dir = temp.path();
+        fs::write(dir.join("x.txt"), "").unwrap();
+        // Writes a depfile next to its output, like a compiler given -MMD
+        let script = dir.join("copy.sh");
+        fs::write(
+            &script,
+            "#!/bin/sh\ncp \"$2\" \"$1\"\necho \"$1: $2\" > \"${1%.out}.d\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(
+            dir.join("tasks.su"),
+            format!(
+                "profile debug {{\n\tX = 1\n}}\nx.out: x.txt\n%.out < %.txt\n\t{} $@ $^\n",
+                script.display()
+            ),
+        )
+        .unwrap();
+
+        let report = crate::Build::new()
+            .source_dir(dir)
+            .profile("debug")
+            .run()
+            .unwrap();
+        assert_eq!(report.built, ["x.out"]);
+        assert!(dir.join("debug/x.d").exists());
+        assert!(read_depfiles(dir).unwrap().is_empty());
+
+        // The profile's depfiles are in a subdirectory of the default build
+        // directory, but don't belong to it
+        let report = crate::Build::new().source_dir(dir).run().unwrap();
+        assert_eq!(report.built, ["x.out"]);
+        assert!(dir.join("x.out").exists());
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+};
+
+/// Everything the commands that last built each target wrote, kept in
+/// `<target>.log` in here.
+const LOGS: &str = ".suru/logs";
+
+/// The log of a target. Only the normal parts of the target's path are kept,
+/// so that targets outside the build directory still get a log inside it.
+pub fn path(builddir: &Path, target: &str) -> PathBuf {
+    let mut path = builddir.join(LOGS);
+    path.extend(Path::new(target).components().filter_map(|c| match c {
+        Component::Normal(c) => Some(c),
+        _ => None,
+    }));
+    path.as_mut_os_string().push(".log");
+    path
+}
+
+/// Replaces the log of a target.
+pub fn write(builddir: &Path, target: &str, log: &[u8]) -> io::Result<()> {
+    let path = path(builddir, target);
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(path, log)
+}
+
+pub fn read(builddir: &Path, target: &str) -> io::Result<Vec<u8>> {
+    fs::read(path(builddir, target))
+}
+
+/// Removes the logs of the given targets, or of every target.
+pub fn remove(builddir: &Path, only: Option<&HashSet<String>>) -> io::Result<()> {
+    let result = match only {
+        Some(targets) => targets
+            .iter()
+            .try_for_each(|t| fs::remove_file(path(builddir, t)).or_else(not_found)),
+        None => fs::remove_dir_all(builddir.join(LOGS)),
+    };
+    result.or_else(not_found)
+}
+
+fn not_found(e: io::Error) -> io::Result<()> {
+    match e.kind() {
+        ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path() {
+        let builddir = Path::new("/build");
+        assert_eq!(
+            path(builddir, "lib/lib.o"),
+            Path::new("/build/.suru/logs/lib/lib.o.log")
+        );
+        assert_eq!(
+            path(builddir, "/elsewhere/../a.out"),
+            Path::new("/build/.suru/logs/elsewhere/a.out.log")
+        );
+    }
+}
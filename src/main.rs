@@ -1,21 +1,150 @@
+#[cfg(target_os = "linux")]
+use std::time::Duration;
 use std::{
-    collections::HashMap,
+    collections::HashSet,
+    io::{IsTerminal, Write},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use indicatif::MultiProgress;
 use indicatif_log_bridge::LogWrapper;
-use log::error;
-use suru::{build::compile, parser::parse};
+use log::{error, info, warn};
+#[cfg(target_os = "linux")]
+use suru::watch::Watcher;
+use suru::{
+    build::{compile, BuildOptions, BuildReport, Graph},
+    check,
+    error::{Result, SuruError},
+    events::JsonLines,
+    fmt, interrupt,
+    load::{
+        find_file, load, preprocess, read_depfiles, read_sufile, search_dependencies,
+        select_profile,
+    },
+    logs, manifest,
+    parser::{Context, TaskFile},
+    query,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Targets to build, defaults to every target. Arguments of the form
+    /// NAME=VALUE set variables instead.
+    targets: Vec<String>,
+    /// Set a variable, overriding declarations in sufiles
+    #[arg(short = 'D', value_name = "NAME=VALUE")]
+    define: Vec<String>,
+    #[arg(short, long, global = true)]
     build_dir: Option<String>,
-    #[arg(short, long)]
+    /// Directory to search for tasks.su in, or the source directory if a
+    /// sufile is given explicitly
+    #[arg(short, long, global = true)]
     source_dir: Option<String>,
+    /// Read this sufile instead of searching for tasks.su, or - to read stdin
+    #[arg(short, long, global = true, value_name = "PATH")]
+    file: Option<PathBuf>,
+    /// Use the variables from this profile in the sufile. The build directory
+    /// defaults to a subdirectory named after the profile.
+    #[arg(short, long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+    /// Print the commands that would be run without running them
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Number of commands to run at once, 0 for no limit
+    #[arg(short, long, env = "SURU_JOBS")]
+    jobs: Option<usize>,
+    /// Don't start new commands while the load average is above this
+    #[arg(short = 'l', long)]
+    load_average: Option<f64>,
+    /// Keep building targets that don't depend on a failed target
+    #[arg(short, long)]
+    keep_going: bool,
+    /// Print why each target is built
+    #[arg(long)]
+    explain: bool,
+    /// Write a Chrome trace of every command run to FILE, and print the
+    /// critical path and slowest targets
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+    /// Build, then build again whenever a source, the sufile or a depfile changes
+    #[arg(short, long)]
+    watch: bool,
+    /// How to report progress. `json` writes one JSON object per line to
+    /// stdout for each thing that happens during the build.
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+    message_format: MessageFormat,
+    /// Print what the commands wrote to stderr for each target that was
+    /// built, such as compiler warnings
+    #[arg(long)]
+    show_warnings: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Remove the files suru created in the build directory
+    Clean {
+        /// Only remove these targets
+        targets: Vec<String>,
+    },
+    /// Inspect the build graph without building anything
+    Query {
+        #[command(subcommand)]
+        query: Query,
+    },
+    /// Rewrite sufiles in canonical form
+    Fmt {
+        /// Sufiles to format, or - to format stdin. Defaults to the sufile
+        /// that would be built.
+        files: Vec<PathBuf>,
+        /// Don't rewrite anything, just list the files that aren't formatted
+        /// and fail if there are any
+        #[arg(long)]
+        check: bool,
+    },
+    /// Look for mistakes in the sufile without building anything
+    Check,
+    /// Print everything the commands that last built a target wrote
+    Log { target: String },
+    /// Print the dependency graph, for rendering or other tools
+    Graph {
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+        /// Only include these targets and the files they depend on
+        targets: Vec<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageFormat {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Query {
+    /// List every target
+    Targets,
+    /// Show the files a target depends on, and where each dependency came from
+    Deps { target: String },
+    /// Show the targets that depend on a file
+    Rdeps {
+        #[arg(value_name = "FILE")]
+        path: String,
+    },
+    /// Show which recipe builds a target, and why it was chosen
+    Recipe { target: String },
 }
 
 fn init_logging() -> MultiProgress {
@@ -35,13 +164,62 @@ fn main() {
     let args = Args::parse();
 
     let mp = init_logging();
+    if let Err(e) = interrupt::install() {
+        warn!(
+            "Unable to handle Ctrl-C, commands may be left running if suru is interrupted: {}",
+            e
+        );
+    }
 
-    let build_root = args
+    if let Err(e) = run(args, mp) {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(mut args: Args, mp: MultiProgress) -> Result<()> {
+    let (assignments, mut targets): (Vec<_>, Vec<_>) = std::mem::take(&mut args.targets)
+        .into_iter()
+        .partition(|t| t.contains('='));
+    let mut context = Context::default();
+    for assignment in args.define.iter().chain(&assignments) {
+        match assignment.split_once('=') {
+            Some((name, value)) if !name.is_empty() => context.set_override(
+                name.trim().to_owned(),
+                value.split_whitespace().map(str::to_owned).collect(),
+            ),
+            _ => {
+                return Err(SuruError::Usage(format!(
+                    "Expected NAME=VALUE, got {}",
+                    assignment
+                )))
+            }
+        }
+    }
+
+    if let Some(dir) = legacy_build_dir(&args, &targets, &context)? {
+        warn!(
+            "Giving the build directory without -b is deprecated, use suru -b {} instead",
+            dir
+        );
+        args.build_dir = targets.pop();
+    }
+
+    let cwd = std::env::current_dir().map_err(SuruError::io(
+        "Unable to open the current working directory",
+    ))?;
+    // Absolute, so that tasks.su is searched for in the parents of a relative
+    // build directory too
+    let build_root = match args
         .build_dir
+        .clone()
         .and_then(|s| PathBuf::from_str(&s).ok())
-        .or(std::env::current_dir().ok())
-        .expect("Unable to open current working directory");
+    {
+        Some(build_root) => cwd.join(build_root),
+        None => cwd,
+    };
 
+    let explicit_source = args.source_dir.is_some();
     let search_root = args
         .source_dir
         .and_then(|s| match PathBuf::from_str(&s) {
@@ -53,120 +231,372 @@ fn main() {
         })
         .unwrap_or_else(|| build_root.clone());
 
-    let taskfile = find_file(&search_root);
+    let build_root = match &args.profile {
+        Some(profile) if args.build_dir.is_none() => build_root.join(profile),
+        _ => build_root,
+    };
 
-    let tasks = std::fs::read(&taskfile).expect("Could not read task file");
-    let mut context = HashMap::<String, Vec<String>>::default();
-    let prologue = include_str!("prologue.su");
-    let mut buildstate = Default::default();
+    if let Some(Command::Clean { targets }) = &args.command {
+        let only = if targets.is_empty() {
+            None
+        } else {
+            let (sufile, sourcedir) = locate(args.file, search_root, explicit_source)?;
+            let buildstate = load(&sufile, args.profile.as_deref(), &build_root, context)?;
+            let graph = Graph::new(buildstate, &build_root, &sourcedir, targets)?;
+            Some(
+                targets
+                    .iter()
+                    .map(|t| graph.name(t))
+                    .collect::<HashSet<_>>(),
+            )
+        };
+        return manifest::clean(&build_root, only.as_ref())
+            .map_err(SuruError::io(format!("Unable to clean {:?}", build_root)));
+    }
 
-    parse(prologue, &mut context, &mut buildstate, "prologue");
+    if let Some(Command::Log { target }) = &args.command {
+        // The log is named after the target relative to the build directory
+        let name = Path::new(target)
+            .strip_prefix(&build_root)
+            .unwrap_or(Path::new(target));
+        let log = logs::read(&build_root, &name.to_string_lossy()).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => SuruError::Usage(format!(
+                "There is no log for {}, it hasn't been built",
+                target
+            )),
+            _ => SuruError::io(format!("Unable to read the log for {}", target))(e),
+        })?;
+        return std::io::stdout()
+            .write_all(&log)
+            .map_err(SuruError::io("Unable to print the log"));
+    }
 
-    let mut depfiles = Vec::new();
-    search_dependencies(&build_root, &mut depfiles);
+    if let Some(Command::Fmt { files, check }) = args.command {
+        let files = if files.is_empty() {
+            vec![locate(args.file, search_root, explicit_source)?.0]
+        } else {
+            files
+        };
+        if !format_files(&files, check)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    for depfile in depfiles {
-        let tasks = std::fs::read(&depfile);
-        match tasks {
-            Err(e) => {
-                error!(
-                    "Unable to read depfile {:?} due to: {}",
-                    depfile.file_name(),
-                    e
-                );
-            }
-            Ok(contents) => {
-                parse(
-                    &preprocess(contents),
-                    &mut context,
-                    &mut buildstate,
-                    &depfile.file_name().unwrap().to_string_lossy(),
-                );
-            }
+    if let Some(Command::Check) = args.command {
+        let (sufile, sourcedir) = locate(args.file, search_root, explicit_source)?;
+        let (tasks, filename) = read_sufile(&sufile)?;
+        if let Some(profile) = &args.profile {
+            select_profile(&preprocess(&tasks), profile, &mut context, &filename)?;
+        }
+        let depfiles = read_depfiles(&build_root)?;
+        let problems = check::check(
+            &filename,
+            &tasks,
+            &depfiles,
+            context,
+            &sourcedir,
+            &build_root,
+        )?;
+        for problem in &problems {
+            println!("{}", problem);
         }
+        if !problems.is_empty() {
+            return Err(SuruError::Graph(format!(
+                "Found {} problem(s)",
+                problems.len()
+            )));
+        }
+        return Ok(());
     }
 
-    parse(
-        &preprocess(tasks),
-        &mut context,
-        &mut buildstate,
-        &taskfile.file_name().unwrap().to_string_lossy(),
-    );
+    let (sufile, sourcedir) = locate(args.file, search_root, explicit_source)?;
 
-    compile(
-        buildstate,
+    let buildstate = load(
+        &sufile,
+        args.profile.as_deref(),
         &build_root,
-        taskfile
-            .parent()
-            .expect("Unable to open parent of task file"),
-        mp,
-    );
+        context.clone(),
+    )?;
+
+    if let Some(Command::Query { query }) = args.command {
+        let graph = Graph::new(buildstate, &build_root, &sourcedir, &[])?;
+        let output = match query {
+            Query::Targets => Ok(query::targets(&graph)),
+            Query::Deps { target } => query::deps(&graph, &graph.name(&target)).ok_or(target),
+            Query::Rdeps { path } => Ok(query::rdeps(&graph, &graph.name(&path))),
+            Query::Recipe { target } => query::recipe(&graph, &graph.name(&target)).ok_or(target),
+        };
+        let output =
+            output.map_err(|target| SuruError::Usage(format!("{} is not a target", target)))?;
+        print!("{}", output);
+        return Ok(());
+    }
+
+    if let Some(Command::Graph { format, targets }) = args.command {
+        let graph = Graph::new(buildstate, &build_root, &sourcedir, &targets)?;
+        match format {
+            GraphFormat::Dot => print!("{}", query::dot(&graph)),
+            GraphFormat::Json => print!("{}", query::json(&graph)),
+        }
+        return Ok(());
+    }
+
+    let options = BuildOptions {
+        targets,
+        dry_run: args.dry_run,
+        jobs: args.jobs,
+        max_load: args.load_average,
+        keep_going: args.keep_going,
+        explain: args.explain,
+        trace: args.trace,
+        listener: match args.message_format {
+            MessageFormat::Json => Some(Arc::new(JsonLines)),
+            MessageFormat::Human => None,
+        },
+        show_warnings: args.show_warnings,
+        progress_lines: !std::io::stdout().is_terminal(),
+    };
+
+    if args.watch {
+        if sufile == Path::new("-") {
+            return Err(SuruError::Usage(
+                "--watch can't be used with a sufile read from stdin".to_owned(),
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        return watch(
+            &sufile,
+            args.profile.as_deref(),
+            &sourcedir,
+            &build_root,
+            context,
+            &options,
+            mp,
+        );
+        #[cfg(not(target_os = "linux"))]
+        return Err(SuruError::Usage(
+            "--watch is only supported on Linux".to_owned(),
+        ));
+    }
+
+    let report = compile(buildstate, &build_root, &sourcedir, &options, mp)?;
+    print_plan(&report);
+    Ok(())
 }
 
-fn find_file(search_root: &Path) -> PathBuf {
-    for parent in search_root.ancestors() {
-        match parent.read_dir() {
-            Ok(d) => {
-                for file in d {
-                    match file {
-                        Ok(f) => {
-                            if f.file_name() == "tasks.su" {
-                                return f.path();
-                            }
-                        }
-                        Err(e) => {
-                            error!(
-                                "Unable to read file {} due to {}",
-                                parent
-                                    .as_os_str()
-                                    .to_str()
-                                    .expect("Unable to parse UTF-8 error message"),
-                                e
-                            )
-                        }
-                    }
-                }
+/// Prints the commands a dry run would run, under a line saying whether each
+/// target is out of date.
+fn print_plan(report: &BuildReport) {
+    for planned in &report.planned {
+        let status = planned.reason.as_deref().unwrap_or("up to date");
+        println!("# {}: {}", planned.target, status);
+        for command in &planned.commands {
+            println!("{}", command);
+        }
+    }
+}
+
+/// How long to wait for more changes before rebuilding in watch mode.
+#[cfg(target_os = "linux")]
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Builds every time a source, the sufile or a depfile changes, forever.
+/// Mistakes in the sufile and failed builds are reported, and then watched
+/// for a fix.
+#[cfg(target_os = "linux")]
+fn watch(
+    sufile: &Path,
+    profile: Option<&str>,
+    sourcedir: &Path,
+    build_root: &Path,
+    context: Context,
+    options: &BuildOptions,
+    mp: MultiProgress,
+) -> Result<()> {
+    // The files to watch, and the depfiles building the targets writes, which
+    // are next to the targets
+    let files = |buildstate: &TaskFile| -> Result<(Vec<PathBuf>, HashSet<PathBuf>)> {
+        let graph = Graph::new(buildstate.clone(), build_root, sourcedir, &options.targets)?;
+        let mut files = graph.sources();
+        files.push(sufile.to_path_buf());
+        search_dependencies(build_root, &mut files)?;
+        let depfiles = graph
+            .targets()
+            .into_iter()
+            .map(|t| build_root.join(t).with_extension("d"))
+            .collect();
+        Ok((files, depfiles))
+    };
+    // Loads the sufile and watches everything it reads. If it can't be
+    // loaded, only the sufile and the depfiles are watched.
+    let reload = |watcher: &mut Watcher| {
+        let loaded = load(sufile, profile, build_root, context.clone())
+            .and_then(|buildstate| Ok((files(&buildstate)?, buildstate)));
+        match loaded {
+            Ok(((files, depfiles), buildstate)) => {
+                watcher.watch(files);
+                Some((buildstate, depfiles))
             }
             Err(e) => {
-                // Don't actually panic because this usually isn't a fatal error
-                error!(
-                    "Unable to search directory {} for build files due to {}",
-                    parent
-                        .as_os_str()
-                        .to_str()
-                        .expect("Unable to parse UTF-8 error message"),
-                    e
-                )
+                error!("{}", e);
+                let mut files = vec![sufile.to_path_buf()];
+                let _ = search_dependencies(build_root, &mut files);
+                watcher.watch(files);
+                None
+            }
+        }
+    };
+    let mut watcher = Watcher::new().map_err(SuruError::io("Unable to watch for changes"))?;
+    loop {
+        // Watching starts before the build, so that changes made during it count
+        let mut written = HashSet::new();
+        if let Some((buildstate, depfiles)) = reload(&mut watcher) {
+            match compile(buildstate, build_root, sourcedir, options, mp.clone()) {
+                Err(SuruError::Interrupted) => return Err(SuruError::Interrupted),
+                Err(e) => error!("{}", e),
+                Ok(report) => print_plan(&report),
+            }
+            // The build can write depfiles that name new sources to watch.
+            // Those depfile changes aren't a reason to build again by
+            // themselves.
+            reload(&mut watcher);
+            written = depfiles;
+        }
+        let mut changed = watcher.changes().unwrap_or_default();
+        changed.retain(|f| !written.contains(f));
+
+        if changed.is_empty() {
+            info!("Watching for changes");
+            changed = watcher
+                .wait(DEBOUNCE)
+                .map_err(SuruError::io("Unable to watch for changes"))?;
+        }
+        changed.sort();
+        changed.dedup();
+        let names: Vec<_> = changed
+            .iter()
+            .map(|f| f.strip_prefix(sourcedir).unwrap_or(f).to_string_lossy())
+            .collect();
+        info!("{} changed, rebuilding", names.join(", "));
+    }
+}
+
+/// Formats each sufile in place, or stdin to stdout for `-`. With `check`, only
+/// lists the files that would change, returning false if there are any.
+fn format_files(files: &[PathBuf], check: bool) -> Result<bool> {
+    let mut formatted = true;
+    for file in files {
+        let (input, name) = read_sufile(file)?;
+        let output = fmt::format(&input, &name)?;
+        if output == input {
+            if file == Path::new("-") && !check {
+                print!("{}", output);
             }
+            continue;
+        }
+        if check {
+            println!("{}", name);
+            formatted = false;
+        } else if file == Path::new("-") {
+            print!("{}", output);
+        } else if let Err(e) = std::fs::write(file, output) {
+            error!("Unable to write {}: {}", name, e);
+            formatted = false;
         }
     }
-    error!("Unable to find tasks.su in any parent directories");
-    panic!();
+    Ok(formatted)
 }
 
-fn search_dependencies(search_root: &Path, out: &mut Vec<PathBuf>) {
-    for file in search_root
-        .read_dir()
-        .expect("Cannot search files in build root")
-        .filter_map(|d| d.ok())
+/// The build directory used to be the only positional argument, which a lone
+/// target that is an existing directory is still taken to be, unless the
+/// sufile has a task of that name.
+fn legacy_build_dir(args: &Args, targets: &[String], context: &Context) -> Result<Option<String>> {
+    let [dir] = targets else {
+        return Ok(None);
+    };
+    if args.command.is_some()
+        || args.build_dir.is_some()
+        || args.file.as_deref() == Some(Path::new("-"))
+        || !Path::new(dir).is_dir()
     {
-        if file.file_type().is_ok_and(|t| t.is_dir()) {
-            search_dependencies(&file.path(), out);
-        } else if file
-            .file_name()
-            .to_str()
-            .and_then(|f| Some(f.ends_with(".d")))
-            .unwrap_or(false)
-        {
-            out.push(file.path());
-        }
+        return Ok(None);
+    }
+    let cwd = std::env::current_dir().map_err(SuruError::io(
+        "Unable to open the current working directory",
+    ))?;
+    let search_root = args.source_dir.as_ref().map_or(cwd.clone(), PathBuf::from);
+    // Without a sufile here, the directory can only be a build directory,
+    // which the sufile is then searched for from
+    let Ok((sufile, _)) = locate(args.file.clone(), search_root, args.source_dir.is_some()) else {
+        return Ok(Some(dir.clone()));
+    };
+    let build_root = match &args.profile {
+        Some(profile) => cwd.join(profile),
+        None => cwd,
+    };
+    let tasks = load(
+        &sufile,
+        args.profile.as_deref(),
+        &build_root,
+        context.clone(),
+    )?;
+    if tasks.tasks.contains_key(dir.trim_end_matches('/')) {
+        return Ok(None);
     }
+    Ok(Some(dir.clone()))
 }
 
-fn preprocess(file: Vec<u8>) -> String {
-    String::from_utf8(file)
-        .expect("Build file is not utf-8")
-        .replace("\\\n", " ")
-        .replace("\\\r\n", " ")
-        + "\n"
+/// Finds the sufile to read, and the source directory it describes. The sufile
+/// is `-` when it should be read from stdin.
+fn locate(
+    file: Option<PathBuf>,
+    search_root: PathBuf,
+    explicit_source: bool,
+) -> Result<(PathBuf, PathBuf)> {
+    let Some(file) = file else {
+        let sufile = find_file(&search_root)?;
+        let sourcedir = sufile.parent().unwrap_or(&search_root).to_path_buf();
+        return Ok((sufile, sourcedir));
+    };
+
+    let sourcedir = if explicit_source {
+        search_root
+    } else if file == Path::new("-") {
+        std::env::current_dir().map_err(SuruError::io(
+            "Unable to open the current working directory",
+        ))?
+    } else {
+        match file.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    };
+    Ok((file, sourcedir))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_options_before_subcommand() {
+        let args = Args::try_parse_from(["suru", "-b", "build", "clean"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Clean { .. })));
+        assert_eq!(args.build_dir.as_deref(), Some("build"));
+        assert!(args.targets.is_empty());
+
+        let args = Args::try_parse_from(["suru", "-s", "src", "query", "targets"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Query {
+                query: Query::Targets
+            })
+        ));
+        assert_eq!(args.source_dir.as_deref(), Some("src"));
+
+        let args = Args::try_parse_from(["suru", "-b", "build", "a", "CC=clang"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.targets, ["a", "CC=clang"]);
+    }
 }
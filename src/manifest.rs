@@ -0,0 +1,171 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{error, info};
+
+use crate::{logs, state};
+
+const MANIFEST: &str = ".suru/manifest";
+
+/// Every file and directory suru has created in a build directory, so that
+/// cleaning never removes anything else.
+///
+/// Each line of the manifest is either `f <path>` for a file or `d <path>` for
+/// a directory, with paths relative to the build directory.
+pub struct Manifest {
+    builddir: PathBuf,
+    state: Mutex<(HashSet<String>, File)>,
+}
+
+impl Manifest {
+    pub fn open(builddir: &Path) -> io::Result<Self> {
+        let path = builddir.join(MANIFEST);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let entries = read_entries(builddir)?.into_iter().collect();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            builddir: builddir.to_path_buf(),
+            state: Mutex::new((entries, file)),
+        })
+    }
+
+    pub fn add_file(&self, path: &Path) {
+        self.add('f', path);
+    }
+
+    pub fn add_dir(&self, path: &Path) {
+        self.add('d', path);
+    }
+
+    fn add(&self, kind: char, path: &Path) {
+        // Anything outside of the build directory is never cleaned up
+        let Ok(relative) = path.strip_prefix(&self.builddir) else {
+            return;
+        };
+        let entry = format!("{} {}", kind, relative.display());
+        let mut state = self.state.lock().expect("Manifest poisoned");
+        let (entries, file) = &mut *state;
+        if entries.insert(entry.clone()) {
+            if let Err(e) = writeln!(file, "{}", entry) {
+                error!("Unable to record {} in the build manifest: {}", entry, e);
+            }
+        }
+    }
+}
+
+fn read_entries(builddir: &Path) -> io::Result<Vec<String>> {
+    match fs::read_to_string(builddir.join(MANIFEST)) {
+        Ok(s) => Ok(s.lines().map(str::to_owned).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes files suru created in `builddir`. If `only` is given, just those
+/// targets are removed, along with the directories suru created for them.
+/// Directories are only removed once they are empty.
+pub fn clean(builddir: &Path, only: Option<&HashSet<String>>) -> io::Result<()> {
+    logs::remove(builddir, only)?;
+    if only.is_none() {
+        state::remove(builddir)?;
+    }
+
+    // The depfiles written alongside the targets go too
+    let depfiles: Option<HashSet<_>> =
+        only.map(|o| o.iter().map(|t| Path::new(t).with_extension("d")).collect());
+    let selected = |path: &str| match (only, &depfiles) {
+        (Some(o), Some(d)) => o.contains(path) || d.contains(Path::new(path)),
+        _ => true,
+    };
+
+    // A directory was created for a target if the target is inside it
+    let created_for =
+        |dir: &str| only.is_none_or(|o| o.iter().any(|t| Path::new(t).starts_with(dir)));
+
+    let mut kept = Vec::new();
+    let mut dirs = Vec::new();
+    for entry in read_entries(builddir)? {
+        match entry.split_once(' ') {
+            Some(("f", path)) if selected(path) => match fs::remove_file(builddir.join(path)) {
+                Ok(()) => info!("Removed {}", path),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            },
+            Some(("d", path)) if created_for(path) => dirs.push(path.to_owned()),
+            _ => kept.push(entry),
+        }
+    }
+
+    // Remove the deepest directories first, so that their parents can be empty
+    dirs.sort_by_key(|d| std::cmp::Reverse(Path::new(d).components().count()));
+    for dir in dirs {
+        match fs::remove_dir(builddir.join(&dir)) {
+            Ok(()) => info!("Removed {}", dir),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(_) => kept.push(format!("d {}", dir)),
+        }
+    }
+
+    let manifest = builddir.join(MANIFEST);
+    if kept.is_empty() {
+        match fs::remove_file(&manifest) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Only succeeds if nothing else is kept in there
+        let _ = fs::remove_dir(manifest.parent().unwrap());
+    } else {
+        let temp = manifest.with_extension("tmp");
+        fs::write(&temp, kept.join("\n") + "\n")?;
+        fs::rename(temp, manifest)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_clean() {
+        let temp = TempDir::new("manifest");
+        let builddir = temp.path();
+        fs::create_dir_all(builddir.join("lib")).unwrap();
+        fs::create_dir_all(builddir.join("empty")).unwrap();
+        fs::write(builddir.join("main.c"), "").unwrap();
+        fs::write(builddir.join("main.o"), "").unwrap();
+        fs::write(builddir.join("lib/lib.o"), "").unwrap();
+
+        let manifest = Manifest::open(builddir).unwrap();
+        manifest.add_file(&builddir.join("main.o"));
+        manifest.add_dir(&builddir.join("lib"));
+        manifest.add_file(&builddir.join("lib/lib.o"));
+        manifest.add_dir(&builddir.join("empty"));
+        drop(manifest);
+        logs::write(builddir, "main.o", b"").unwrap();
+        logs::write(builddir, "lib/lib.o", b"").unwrap();
+
+        clean(builddir, Some(&HashSet::from(["main.o".to_string()]))).unwrap();
+        assert!(!builddir.join("main.o").exists());
+        assert!(builddir.join("lib/lib.o").exists());
+        assert!(!logs::path(builddir, "main.o").exists());
+        assert!(logs::path(builddir, "lib/lib.o").exists());
+        // Wasn't created for main.o, even though it's empty
+        assert!(builddir.join("empty").exists());
+
+        clean(builddir, Some(&HashSet::from(["lib/lib.o".to_string()]))).unwrap();
+        assert!(!builddir.join("lib").exists());
+        assert!(builddir.join("empty").exists());
+
+        clean(builddir, None).unwrap();
+        assert!(!builddir.join("empty").exists());
+        assert!(!builddir.join(".suru").exists());
+        assert!(builddir.join("main.c").exists());
+    }
+}
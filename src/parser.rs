@@ -1,4 +1,4 @@
-use std::{collections::HashMap, hash::Hash};
+use std::collections::{HashMap, HashSet};
 
 use pest::{
     iterators::{Pair, Pairs},
@@ -6,6 +6,7 @@ use pest::{
 };
 use pest_derive::Parser;
 
+use crate::error::{Result, SuruError};
 use crate::functions::eval_function;
 use crate::util::remove_prefix;
 
@@ -13,71 +14,231 @@ use crate::util::remove_prefix;
 #[grammar = "sufile.pest"]
 struct TaskParser;
 
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Task {
     pub inputs: Vec<String>,
+    /// The files each input was declared in.
+    pub origins: HashMap<String, Vec<String>>,
 }
 
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Recipe {
     pub templ_in: Vec<String>,
     pub any_in: Vec<String>,
     pub steps: Vec<Vec<String>>,
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
+impl Recipe {
+    /// Renders the recipe's patterns the way they are written in a sufile,
+    /// given the extension of the targets it builds.
+    pub fn describe(&self, target: &str) -> String {
+        let pattern = |prefix: &str, ext: &str| {
+            if ext == "%" || ext == "*" {
+                ext.to_owned()
+            } else {
+                format!("{}.{}", prefix, ext)
+            }
+        };
+        let inputs = self
+            .templ_in
+            .iter()
+            .map(|ext| pattern("%", ext))
+            .chain(self.any_in.iter().map(|ext| pattern("*", ext)));
+        std::iter::once(pattern("%", target))
+            .chain(std::iter::once("<".to_owned()))
+            .chain(inputs)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct TaskFile {
     pub tasks: HashMap<String, Task>,
     pub recipes: HashMap<String, Vec<Recipe>>,
+    /// Targets that are kept when the command building them fails.
+    pub precious: HashSet<String>,
+}
+
+/// The variables visible to a sufile.
+#[derive(Debug, Default, Clone)]
+pub struct Context {
+    variables: HashMap<String, Vec<String>>,
+    /// Variables set on the command line, which declarations in sufiles don't replace.
+    overrides: HashSet<String>,
+}
+
+impl Context {
+    pub fn get(&self, name: &str) -> Option<&Vec<String>> {
+        self.variables.get(name)
+    }
+
+    /// Sets a variable unless it has been overridden.
+    pub fn insert(&mut self, name: String, value: Vec<String>) {
+        if !self.overrides.contains(&name) {
+            self.variables.insert(name, value);
+        }
+    }
+
+    /// Sets a variable that takes precedence over any later declaration.
+    pub fn set_override(&mut self, name: String, value: Vec<String>) {
+        self.overrides.insert(name.clone());
+        self.variables.insert(name, value);
+    }
+
+    /// Like [`Context::set_override`], but leaves variables that are already
+    /// overridden alone.
+    fn layer(&mut self, name: String, value: Vec<String>) {
+        if !self.overrides.contains(&name) {
+            self.set_override(name, value);
+        }
+    }
+}
+
+/// The built-in recipes and variables, which are read before anything else.
+pub const PROLOGUE: &str = include_str!("prologue.su");
+
+/// Joins lines ending in `\` with the next line, which has to happen before a
+/// sufile is parsed.
+pub fn join_lines(input: &str) -> String {
+    input.replace("\\\n", " ").replace("\\\r\n", " ")
+}
+
+/// Parses a sufile into statements without evaluating anything.
+pub(crate) fn statements<'a>(input: &'a str, filename: &str) -> Result<Pairs<'a, Rule>> {
+    let mut input = TaskParser::parse(Rule::file, input).map_err(|e| SuruError::Parse {
+        file: filename.to_owned(),
+        message: e.to_string(),
+    })?;
+    let file = input
+        .next()
+        .expect("A parsed file always contains the file rule");
+    Ok(file.into_inner())
 }
 
 pub fn parse(
     input: &str,
-    context: &mut HashMap<String, Vec<String>>,
+    context: &mut Context,
+    base: &mut TaskFile,
+    filename: &str,
+) -> Result<()> {
+    for statement in statements(input, filename)? {
+        parse_statement(statement, context, base, filename)?;
+    }
+    Ok(())
+}
+
+/// Adds a single statement of a sufile to `base`, evaluating it in `context`.
+pub(crate) fn parse_statement(
+    statement: Pair<Rule>,
+    context: &mut Context,
     base: &mut TaskFile,
     filename: &str,
-) {
-    let TaskFile { tasks, recipes } = base;
-    let mut input = TaskParser::parse(Rule::file, input)
-        .unwrap_or_else(|e| panic!("error parsing {}, {}", filename, e));
-    let file = input.next().unwrap_or_else(|| (panic!()));
-    for statement in file.into_inner() {
-        match statement.as_rule() {
-            Rule::task => {
-                let mut inners = statement.into_inner();
-                let task = fst(&eval_expr(
-                    &inners.next().unwrap_or_else(|| panic!("match task fail")),
-                    &context,
-                ));
-                let t = tasks.entry(task).or_default();
-                inners
-                    .map(|n| eval_expr(&n, &context))
-                    .filter(|v| !v.is_empty())
-                    .for_each(|mut v| t.inputs.append(&mut v));
+) -> Result<()> {
+    let line = statement.as_span().start_pos().line_col().0;
+    let TaskFile {
+        tasks,
+        recipes,
+        precious,
+    } = base;
+    match statement.as_rule() {
+        Rule::task => {
+            let mut inners = statement.into_inner();
+            let task = fst(eval_expr(
+                &inners.next().unwrap_or_else(|| panic!("match task fail")),
+                context,
+            )
+            .map_err(|e| e.at(filename, line))?)
+            .map_err(|e| e.at(filename, line))?;
+            let mut inputs = Vec::new();
+            for input in inners {
+                inputs.extend(eval_expr(&input, context).map_err(|e| e.at(filename, line))?);
             }
-            Rule::recipe => {
-                let (r, s) = match_recipe(&mut statement.into_inner(), &context);
-                recipes.entry(r).or_default().push(s);
+            let t = tasks.entry(task).or_default();
+            for input in inputs {
+                let origins = t.origins.entry(input.clone()).or_default();
+                if !origins.iter().any(|o| o == filename) {
+                    origins.push(filename.to_owned());
+                }
+                t.inputs.push(input);
             }
-            Rule::vardecl => {
-                match_vardecl(&mut statement.into_inner(), context);
+        }
+        Rule::recipe => {
+            let (r, s) = match_recipe(&mut statement.into_inner(), context)
+                .map_err(|e| e.at(filename, line))?;
+            recipes.entry(r).or_default().push(s);
+        }
+        Rule::vardecl => {
+            match_vardecl(&mut statement.into_inner(), context)
+                .map_err(|e| e.at(filename, line))?;
+        }
+        Rule::precious => {
+            for target in statement.into_inner() {
+                precious.extend(eval_expr(&target, context).map_err(|e| e.at(filename, line))?);
             }
-            Rule::EOI => {}
-            unknown => panic!("This should never occur {:?}", unknown),
         }
+        // Applied separately by apply_profile
+        Rule::profile => {}
+        Rule::EOI => {}
+        unknown => unreachable!("This should never occur {:?}", unknown),
+    }
+    Ok(())
+}
+
+/// The names of the profiles declared in a sufile, in the order they appear.
+pub fn profiles(input: &str, filename: &str) -> Result<Vec<String>> {
+    Ok(profile_decls(input, filename)?
+        .map(|p| p.into_inner().next().unwrap().as_str().to_owned())
+        .collect())
+}
+
+/// Sets the variables declared by a profile so that they take precedence over
+/// declarations in sufiles, but not over variables set on the command line.
+/// This has to happen before anything is parsed, since variables are expanded
+/// as they are parsed. Returns false if there is no such profile.
+pub fn apply_profile(
+    input: &str,
+    name: &str,
+    context: &mut Context,
+    filename: &str,
+) -> Result<bool> {
+    let Some(profile) = profile_decls(input, filename)?
+        .find(|p| p.clone().into_inner().next().unwrap().as_str() == name)
+    else {
+        return Ok(false);
+    };
+    for vardecl in profile.into_inner().skip(1) {
+        let line = vardecl.as_span().start_pos().line_col().0;
+        let (variable, value) =
+            eval_vardecl(&mut vardecl.into_inner(), context).map_err(|e| e.at(filename, line))?;
+        context.layer(variable, value);
     }
+    Ok(true)
+}
+
+fn profile_decls<'a>(
+    input: &'a str,
+    filename: &str,
+) -> Result<impl Iterator<Item = Pair<'a, Rule>>> {
+    Ok(statements(input, filename)?.filter(|statement| statement.as_rule() == Rule::profile))
+}
+
+fn match_vardecl(var: &mut Pairs<Rule>, context: &mut Context) -> Result<()> {
+    let (variable, result) = eval_vardecl(var, context)?;
+    context.insert(variable, result);
+    Ok(())
 }
 
-fn match_vardecl(var: &mut Pairs<Rule>, context: &mut HashMap<String, Vec<String>>) {
+fn eval_vardecl(var: &mut Pairs<Rule>, context: &Context) -> Result<(String, Vec<String>)> {
     let variable = var.next().unwrap_or_else(|| panic!("match vardecl fail"));
-    let result = var.flat_map(|expr| match_step(&expr, context)).collect();
-    context.insert(variable.as_str().to_string(), result);
+    let mut result = Vec::new();
+    for expr in var {
+        result.extend(match_step(&expr, context)?);
+    }
+    Ok((variable.as_str().to_string(), result))
 }
 
-fn match_recipe(
-    recipe: &mut Pairs<Rule>,
-    context: &HashMap<String, Vec<String>>,
-) -> (String, Recipe) {
+fn match_recipe(recipe: &mut Pairs<Rule>, context: &Context) -> Result<(String, Recipe)> {
     let target = recipe
         .next()
         .unwrap_or_else(|| panic!("match template fail"));
@@ -96,61 +257,66 @@ fn match_recipe(
                 }
             }
             Rule::recipe_step => {
-                steps.push(
-                    stuff
-                        .into_inner()
-                        .flat_map(|e| match_step(&e, context))
-                        .collect(),
-                );
+                let mut step = Vec::new();
+                for e in stuff.into_inner() {
+                    step.extend(match_step(&e, context)?);
+                }
+                steps.push(step);
             }
-            _ => panic!("This shouldn't happen"),
+            _ => unreachable!("This shouldn't happen"),
         }
     }
     templ_in.dedup();
     any_in.dedup();
-    (
+    Ok((
         remove_prefix(target.as_str()).to_owned(),
         Recipe {
             templ_in,
             any_in,
             steps,
         },
-    )
+    ))
 }
 
-fn match_step(step: &Pair<Rule>, context: &HashMap<String, Vec<String>>) -> Vec<String> {
+fn match_step(step: &Pair<Rule>, context: &Context) -> Result<Vec<String>> {
     match step.as_rule() {
         Rule::expr => eval_expr(step, context),
-        Rule::implicit_var => vec![step.as_str().to_string()],
-        _ => panic!("This shouldn't happen"),
+        Rule::implicit_var => Ok(vec![step.as_str().to_string()]),
+        _ => unreachable!("This shouldn't happen"),
     }
 }
 
-fn eval_expr(expr: &Pair<Rule>, context: &HashMap<String, Vec<String>>) -> Vec<String> {
+pub(crate) fn eval_expr(expr: &Pair<Rule>, context: &Context) -> Result<Vec<String>> {
     assert!(expr.as_rule() == Rule::expr);
     let mut insides = expr.clone().into_inner();
     let inside = insides.next();
     if let Some(first) = inside {
         let others = insides;
         if others.len() != 0 {
-            let a = others
-                .flat_map(|a| eval_expr(&a, context))
-                .collect::<Vec<_>>();
+            let mut a = Vec::new();
+            for other in others {
+                a.extend(eval_expr(&other, context)?);
+            }
             eval_function(first.as_str(), &a)
         } else {
             context
                 .get(first.as_str())
-                .expect(&format!("Variable {} not found", first.as_str()))
-                .clone()
+                .cloned()
+                .ok_or_else(|| SuruError::eval(format!("{} is not defined", first.as_str())))
         }
     } else {
-        vec![expr.as_str().to_string()]
+        Ok(vec![expr.as_str().to_string()])
     }
 }
 
-fn fst(vec: &Vec<String>) -> String {
-    assert!(vec.len() == 1);
-    vec[0].clone()
+fn fst(vec: Vec<String>) -> Result<String> {
+    match <[String; 1]>::try_from(vec) {
+        Ok([target]) => Ok(target),
+        Err(vec) => Err(SuruError::eval(format!(
+            "A task has to have exactly one target, not {}",
+            vec.len()
+        ))),
+    }
 }
 
 #[cfg(test)]
@@ -162,22 +328,26 @@ mod test {
     #[test]
     fn try_parse() {
         let f = include_str!("test/tasks.su");
-        let mut context = HashMap::new();
+        let mut context = Context::default();
         context.insert(
             "LINKFLAGS".into(),
-            vec!["-MMD", "-lto", "-O3"]
+            ["-MMD", "-lto", "-O3"]
                 .iter()
                 .map(|s| s.to_string())
                 .collect(),
         );
 
         let mut result = TaskFile::default();
-        parse(f, &mut context, &mut result, "test");
+        parse(f, &mut context, &mut result, "test").unwrap();
         let expected = TaskFile {
             tasks: HashMap::from([(
                 "a.exe".into(),
                 Task {
                     inputs: make_svec(&["main.o", "lib/lib.o"]),
+                    origins: HashMap::from([
+                        ("main.o".into(), make_svec(&["test"])),
+                        ("lib/lib.o".into(), make_svec(&["test"])),
+                    ]),
                 },
             )]),
             recipes: HashMap::from([(
@@ -199,6 +369,7 @@ mod test {
                     },
                 ],
             )]),
+            ..Default::default()
         };
         assert_eq!(
             result, expected,
@@ -207,6 +378,84 @@ mod test {
         );
     }
 
+    #[test]
+    fn override_variables() {
+        let mut context = Context::default();
+        context.set_override("CFLAGS".into(), make_svec(&["-O0", "-g"]));
+
+        let mut result = TaskFile::default();
+        parse(
+            "CFLAGS = -O3\nFLAGS = $(CFLAGS)\n",
+            &mut context,
+            &mut result,
+            "test",
+        )
+        .unwrap();
+        assert_eq!(context.get("CFLAGS"), Some(&make_svec(&["-O0", "-g"])));
+        assert_eq!(context.get("FLAGS"), Some(&make_svec(&["-O0", "-g"])));
+    }
+
+    #[test]
+    fn profile_variables() {
+        let input = "profile debug {\n\tCFLAGS = -O0 -g\n\tLDFLAGS = $(CFLAGS)\n}\n\
+                     profile release { CFLAGS = -O3 }\n\
+                     CFLAGS = -O2\n\
+                     a: main.o\n";
+        assert_eq!(
+            profiles(input, "test").unwrap(),
+            make_svec(&["debug", "release"])
+        );
+
+        let mut context = Context::default();
+        context.set_override("LDFLAGS".into(), make_svec(&["-s"]));
+        assert!(apply_profile(input, "debug", &mut context, "test").unwrap());
+        assert!(!apply_profile(input, "fast", &mut context, "test").unwrap());
+
+        let mut result = TaskFile::default();
+        parse(input, &mut context, &mut result, "test").unwrap();
+        assert_eq!(context.get("CFLAGS"), Some(&make_svec(&["-O0", "-g"])));
+        assert_eq!(context.get("LDFLAGS"), Some(&make_svec(&["-s"])));
+        assert_eq!(result.tasks["a"].inputs, make_svec(&["main.o"]));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut context = Context::default();
+        let mut result = TaskFile::default();
+        let error = parse("A = 1\nB = $(C)\n", &mut context, &mut result, "test").unwrap_err();
+        assert_eq!(error.to_string(), "test:2: C is not defined");
+        assert_eq!(error.exit_code(), 2);
+
+        let error = parse("a: $(oops x)\n", &mut context, &mut result, "test").unwrap_err();
+        assert_eq!(error.to_string(), "test:1: Unknown function oops");
+
+        let error = parse("a b c\n", &mut context, &mut result, "test").unwrap_err();
+        assert!(matches!(error, SuruError::Parse { .. }));
+    }
+
+    #[test]
+    fn parse_precious() {
+        let mut context = Context::default();
+        let mut result = TaskFile::default();
+        let sufile = "LIB = libfoo.a\nprecious = 1\nprecious: $(precious)\nprecious a.out $(LIB)\n";
+        parse(sufile, &mut context, &mut result, "test").unwrap();
+        assert_eq!(
+            result.precious,
+            HashSet::from(["a.out".to_owned(), "libfoo.a".to_owned()])
+        );
+        assert_eq!(result.tasks["precious"].inputs, ["1"]);
+    }
+
+    #[test]
+    fn describe_recipe() {
+        let recipe = Recipe {
+            templ_in: make_svec(&["h.in"]),
+            any_in: make_svec(&["sh"]),
+            steps: vec![],
+        };
+        assert_eq!(recipe.describe("h"), "%.h < %.h.in *.sh");
+    }
+
     #[test]
     fn parse_file() {
         let f = include_str!("test/tasks.su");
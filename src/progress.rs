@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+/// The status area of a build: a line counting targets, with an estimate of
+/// the time left, followed by a line for each command that is running. When
+/// `lines` is set, a `[12/340] CC lib/lib.o` line is printed to stdout for each
+/// command instead.
+pub struct Progress {
+    mp: MultiProgress,
+    summary: ProgressBar,
+    lines: bool,
+    jobs: usize,
+    total: usize,
+    /// How long each target took last time.
+    previous: HashMap<String, Duration>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    built: usize,
+    up_to_date: usize,
+    failed: usize,
+    skipped: usize,
+    /// Targets that haven't finished yet.
+    remaining: HashSet<String>,
+    running: HashMap<String, Job>,
+}
+
+struct Job {
+    start: Instant,
+    bar: ProgressBar,
+}
+
+/// How a target finished.
+pub enum Outcome {
+    Built,
+    UpToDate,
+    Failed,
+    /// A target it depends on failed.
+    Skipped,
+}
+
+impl Progress {
+    pub fn new(
+        mp: MultiProgress,
+        targets: impl IntoIterator<Item = String>,
+        jobs: usize,
+        lines: bool,
+        previous: HashMap<String, Duration>,
+    ) -> Self {
+        let remaining: HashSet<_> = targets.into_iter().collect();
+        let total = remaining.len();
+        let summary = if lines {
+            ProgressBar::hidden()
+        } else {
+            let bar = mp.add(ProgressBar::new(total as u64));
+            bar.set_style(
+                ProgressStyle::with_template("{bar:30} {pos}/{len} {msg}")
+                    .expect("The template is valid"),
+            );
+            bar
+        };
+        let progress = Self {
+            mp,
+            summary,
+            lines,
+            jobs: jobs.max(1),
+            total,
+            previous,
+            state: Mutex::new(State {
+                remaining,
+                ..Default::default()
+            }),
+        };
+        progress.update(&progress.state());
+        progress
+    }
+
+    /// A command building `target` is about to run.
+    pub fn started(&self, target: &str, command: &[impl AsRef<OsStr>]) {
+        let label = label(target, command);
+        let mut state = self.state();
+        if self.lines {
+            let (position, total) = self.position(&state, target);
+            let mut stdout = io::stdout().lock();
+            let _ = writeln!(stdout, "[{}/{}] {}", position, total, label);
+            let _ = stdout.flush();
+        }
+        match state.running.get(target) {
+            Some(job) => job.bar.set_message(label),
+            None => {
+                let bar = if self.lines {
+                    ProgressBar::hidden()
+                } else {
+                    let bar = self.mp.add(ProgressBar::new_spinner());
+                    bar.set_style(
+                        ProgressStyle::with_template("  {spinner} {elapsed:>4} {msg}")
+                            .expect("The template is valid"),
+                    );
+                    bar.enable_steady_tick(Duration::from_millis(100));
+                    bar
+                };
+                bar.set_message(label);
+                let job = Job {
+                    start: Instant::now(),
+                    bar,
+                };
+                state.running.insert(target.to_owned(), job);
+            }
+        }
+        self.update(&state);
+    }
+
+    pub fn finished(&self, target: &str, outcome: Outcome) {
+        let mut state = self.state();
+        if !state.remaining.remove(target) {
+            return;
+        }
+        if let Some(job) = state.running.remove(target) {
+            job.bar.finish_and_clear();
+            self.mp.remove(&job.bar);
+        }
+        match outcome {
+            Outcome::Built => state.built += 1,
+            Outcome::UpToDate => state.up_to_date += 1,
+            Outcome::Failed => state.failed += 1,
+            Outcome::Skipped => state.skipped += 1,
+        }
+        self.update(&state);
+        self.summary.inc(1);
+    }
+
+    /// Hides the status area while `f` prints something.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.mp.suspend(f)
+    }
+
+    /// Clears the status area.
+    pub fn finish(&self) {
+        let mut state = self.state();
+        for (_, job) in state.running.drain() {
+            job.bar.finish_and_clear();
+            self.mp.remove(&job.bar);
+        }
+        self.summary.finish_and_clear();
+        self.mp.remove(&self.summary);
+    }
+
+    /// The number of a target that is starting among those that have run, and
+    /// the number that will run. Targets that were up to date or skipped
+    /// aren't counted in either, so the numbers don't jump ahead.
+    fn position(&self, state: &State, target: &str) -> (usize, usize) {
+        let position = state.built
+            + state.failed
+            + state.running.len()
+            + usize::from(!state.running.contains_key(target));
+        (position, self.total - state.up_to_date - state.skipped)
+    }
+
+    fn update(&self, state: &State) {
+        if self.lines {
+            return;
+        }
+        let remaining = state.remaining.len() - state.running.len();
+        let mut message = format!(
+            "{} done, {} up to date, {} running, {} remaining",
+            state.built,
+            state.up_to_date,
+            state.running.len(),
+            remaining
+        );
+        if state.failed > 0 {
+            message += &format!(", {} failed", state.failed);
+        }
+        if state.skipped > 0 {
+            message += &format!(", {} skipped", state.skipped);
+        }
+        if let Some(eta) = self.eta(state).filter(|eta| !eta.is_zero()) {
+            let eta = Duration::from_secs(eta.as_secs_f64().ceil() as u64);
+            message += &format!(", about {} left", humantime::format_duration(eta));
+        }
+        self.summary.set_message(message);
+    }
+
+    /// Estimates how long the unfinished targets will take from how long they
+    /// took last time, assuming every job is kept busy. Targets that weren't
+    /// built last time are assumed to take as long as the average one did.
+    fn eta(&self, state: &State) -> Option<Duration> {
+        if self.previous.is_empty() {
+            return None;
+        }
+        let average = self.previous.values().sum::<Duration>() / self.previous.len() as u32;
+        let work: Duration = state
+            .remaining
+            .iter()
+            .map(|target| {
+                let expected = self.previous.get(target).copied().unwrap_or(average);
+                match state.running.get(target) {
+                    Some(job) => expected.saturating_sub(job.start.elapsed()),
+                    None => expected,
+                }
+            })
+            .sum();
+        let jobs = self.jobs.min(state.remaining.len()).max(1);
+        Some(work / jobs as u32)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Progress poisoned")
+    }
+}
+
+/// Describes a command by the program it runs and the target it builds, such
+/// as `GCC lib/lib.o`.
+fn label(target: &str, command: &[impl AsRef<OsStr>]) -> String {
+    let program = command
+        .first()
+        .map(|p| Path::new(p.as_ref()))
+        .and_then(Path::file_name)
+        .map(|p| p.to_string_lossy().to_uppercase())
+        .unwrap_or_default();
+    format!("{} {}", program, target)
+}
+
+#[cfg(test)]
+mod test {
+    use indicatif::ProgressDrawTarget;
+
+    use super::*;
+
+    #[test]
+    fn test_eta() {
+        let previous = HashMap::from([
+            ("a".to_string(), Duration::from_secs(10)),
+            ("main.o".to_string(), Duration::from_secs(4)),
+            ("lib.o".to_string(), Duration::from_secs(2)),
+        ]);
+        let targets = ["a", "main.o", "lib.o", "new.o"].map(String::from);
+        let progress = Progress::new(
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            targets,
+            2,
+            false,
+            previous,
+        );
+        // new.o wasn't built last time, so it is expected to take the average
+        assert_eq!(
+            progress.eta(&progress.state()),
+            Some((Duration::from_secs(16) + Duration::from_secs(16) / 3) / 2)
+        );
+
+        progress.finished("main.o", Outcome::UpToDate);
+        progress.finished("new.o", Outcome::Built);
+        progress.finished("lib.o", Outcome::Failed);
+        assert_eq!(
+            progress.eta(&progress.state()),
+            Some(Duration::from_secs(10))
+        );
+        let state = progress.state();
+        assert_eq!((state.built, state.up_to_date, state.failed), (1, 1, 1));
+        assert_eq!(progress.summary.position(), 3);
+    }
+
+    #[test]
+    fn test_position() {
+        let targets = ["a", "main.o", "lib.o", "new.o"].map(String::from);
+        let progress = Progress::new(
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            targets,
+            2,
+            true,
+            HashMap::new(),
+        );
+        progress.finished("main.o", Outcome::UpToDate);
+        // As started would, without printing the line
+        let job = Job {
+            start: Instant::now(),
+            bar: ProgressBar::hidden(),
+        };
+        progress.state().running.insert("lib.o".to_owned(), job);
+        assert_eq!(progress.position(&progress.state(), "lib.o"), (1, 3));
+        assert_eq!(progress.position(&progress.state(), "new.o"), (2, 3));
+        progress.finished("lib.o", Outcome::Failed);
+        progress.finished("a", Outcome::Skipped);
+        assert_eq!(progress.position(&progress.state(), "new.o"), (2, 2));
+    }
+
+    #[test]
+    fn test_label() {
+        assert_eq!(
+            label("lib/lib.o", &["/usr/bin/cc", "-c", "lib/lib.c"]),
+            "CC lib/lib.o"
+        );
+    }
+}
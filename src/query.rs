@@ -0,0 +1,323 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+};
+
+use serde::Serialize;
+
+use crate::build::{Graph, Input};
+
+/// Lists every target in the graph.
+pub fn targets(graph: &Graph) -> String {
+    graph.targets().iter().map(|t| format!("{}\n", t)).collect()
+}
+
+/// Shows the tree of files a target depends on, and where each dependency was
+/// declared. Returns `None` if there is no such target.
+pub fn deps(graph: &Graph, target: &str) -> Option<String> {
+    graph.dependencies(target)?;
+    let mut out = format!("{}\n", target);
+    dep_tree(graph, target, 1, &mut HashSet::new(), &mut out);
+    Some(out)
+}
+
+fn dep_tree(
+    graph: &Graph,
+    target: &str,
+    depth: usize,
+    expanded: &mut HashSet<String>,
+    out: &mut String,
+) {
+    let mut listed = HashSet::new();
+    for input in graph.dependencies(target).unwrap_or_default() {
+        if !listed.insert(input.file.clone()) {
+            continue;
+        }
+        let kind = if input.generated {
+            "generated"
+        } else {
+            "source"
+        };
+        write!(
+            out,
+            "{}{} ({}, from {})",
+            "  ".repeat(depth),
+            input.file,
+            kind,
+            input.origins.join(", ")
+        )
+        .unwrap();
+        if input.generated && !expanded.insert(input.file.clone()) {
+            // Already shown further up
+            out.push_str(" ...\n");
+            continue;
+        }
+        out.push('\n');
+        if input.generated {
+            dep_tree(graph, &input.file, depth + 1, expanded, out);
+        }
+    }
+}
+
+/// Shows the tree of targets that depend on a file.
+pub fn rdeps(graph: &Graph, file: &str) -> String {
+    let mut out = format!("{}\n", file);
+    rdep_tree(graph, file, 1, &mut HashSet::new(), &mut out);
+    out
+}
+
+fn rdep_tree(
+    graph: &Graph,
+    file: &str,
+    depth: usize,
+    expanded: &mut HashSet<String>,
+    out: &mut String,
+) {
+    for dependent in graph.dependents(file) {
+        write!(out, "{}{}", "  ".repeat(depth), dependent).unwrap();
+        if !expanded.insert(dependent.to_owned()) {
+            out.push_str(" ...\n");
+            continue;
+        }
+        out.push('\n');
+        rdep_tree(graph, dependent, depth + 1, expanded, out);
+    }
+}
+
+/// Explains which recipe builds a target and why it was chosen. Returns `None`
+/// if there is no such target.
+pub fn recipe(graph: &Graph, target: &str) -> Option<String> {
+    if !graph.contains(target) {
+        return None;
+    }
+    let Some(recipe) = graph.recipe(target) else {
+        return Some(format!("No recipe matches {}\n", target));
+    };
+
+    let mut out = format!("{} is built by {}\n", target, recipe.recipe);
+    for (file, pattern) in &recipe.matched {
+        writeln!(out, "  {} matches {}", file, pattern).unwrap();
+    }
+    for rejected in &recipe.rejected {
+        writeln!(out, "{} was skipped, none of the inputs match it", rejected).unwrap();
+    }
+    out.push_str("Commands:\n");
+    for command in &recipe.commands {
+        writeln!(out, "  {}", command).unwrap();
+    }
+    Some(out)
+}
+
+/// Every target and its inputs, without repeats, sorted by target name.
+fn edges(graph: &Graph) -> Vec<(&str, Vec<Input>)> {
+    graph
+        .targets()
+        .into_iter()
+        .map(|t| {
+            let mut listed = HashSet::new();
+            let mut inputs = graph.dependencies(t).unwrap_or_default();
+            inputs.retain(|i| listed.insert(i.file.clone()));
+            (t, inputs)
+        })
+        .collect()
+}
+
+/// Writes the graph in Graphviz's DOT format, with an edge from each target to
+/// each of its inputs. Sources are drawn as boxes.
+pub fn dot(graph: &Graph) -> String {
+    let mut out = String::from("digraph suru {\n");
+    let mut sources = Vec::new();
+    for (target, inputs) in edges(graph) {
+        writeln!(out, "    {};", dot_id(target)).unwrap();
+        for input in inputs {
+            writeln!(out, "    {} -> {};", dot_id(target), dot_id(&input.file)).unwrap();
+            if !input.generated && !sources.contains(&input.file) {
+                sources.push(input.file);
+            }
+        }
+    }
+    sources.sort();
+    for source in sources {
+        writeln!(out, "    {} [shape=box];", dot_id(&source)).unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[derive(Serialize)]
+struct JsonTarget<'a> {
+    dependencies: Vec<JsonInput>,
+    dependents: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonInput {
+    file: String,
+    /// Either `source` or `generated`
+    kind: &'static str,
+    origins: Vec<String>,
+}
+
+/// Writes the graph as a JSON object, with a key for each target.
+pub fn json(graph: &Graph) -> String {
+    let edges = edges(graph);
+    let mut targets: BTreeMap<&str, JsonTarget> = edges
+        .iter()
+        .map(|(t, _)| {
+            let target = JsonTarget {
+                dependencies: Vec::new(),
+                dependents: Vec::new(),
+            };
+            (*t, target)
+        })
+        .collect();
+    for (target, inputs) in &edges {
+        for input in inputs {
+            if let Some(dependency) = targets.get_mut(input.file.as_str()) {
+                dependency.dependents.push(target);
+            }
+        }
+    }
+    for (target, inputs) in edges {
+        targets.get_mut(target).unwrap().dependencies = inputs
+            .into_iter()
+            .map(|i| JsonInput {
+                kind: if i.generated { "generated" } else { "source" },
+                file: i.file,
+                origins: i.origins,
+            })
+            .collect();
+    }
+    serde_json::to_string_pretty(&serde_json::json!({ "targets": targets })).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, path::Path};
+
+    use crate::{
+        parser::{Recipe, Task, TaskFile},
+        util::make_svec,
+    };
+
+    use super::*;
+
+    fn task(inputs: &[&str], origin: &str) -> Task {
+        Task {
+            inputs: make_svec(inputs),
+            origins: inputs
+                .iter()
+                .map(|i| (i.to_string(), make_svec(&[origin])))
+                .collect(),
+        }
+    }
+
+    fn graph() -> Graph {
+        let input = TaskFile {
+            tasks: HashMap::from([
+                ("a".into(), task(&["main.o", "lib.o"], "tasks.su")),
+                ("main.o".into(), task(&["main.c", "lib.h"], "main.d")),
+                ("lib.o".into(), task(&["lib.c", "lib.h"], "lib.d")),
+            ]),
+            recipes: HashMap::from([
+                (
+                    "o".into(),
+                    vec![
+                        Recipe {
+                            templ_in: make_svec(&["cpp"]),
+                            any_in: vec![],
+                            steps: vec![make_svec(&["g++", "-c", "$^", "-o", "$@"])],
+                        },
+                        Recipe {
+                            templ_in: make_svec(&["c"]),
+                            any_in: vec![],
+                            steps: vec![make_svec(&["gcc", "-c", "$^", "-o", "$@"])],
+                        },
+                    ],
+                ),
+                (
+                    "%".into(),
+                    vec![Recipe {
+                        templ_in: vec![],
+                        any_in: make_svec(&["o"]),
+                        steps: vec![make_svec(&["gcc", "-o", "$@", "$^"])],
+                    }],
+                ),
+            ]),
+            ..Default::default()
+        };
+        Graph::new(input, Path::new("build"), Path::new("src"), &[]).unwrap()
+    }
+
+    #[test]
+    fn test_deps() {
+        let graph = graph();
+        assert_eq!(targets(&graph), "a\nlib.o\nmain.o\n");
+        assert_eq!(
+            deps(&graph, "a").unwrap(),
+            "a
+  main.o (generated, from tasks.su)
+    main.c (source, from main.d)
+    lib.h (source, from main.d)
+  lib.o (generated, from tasks.su)
+    lib.c (source, from lib.d)
+    lib.h (source, from lib.d)
+"
+        );
+        assert_eq!(
+            rdeps(&graph, "lib.h"),
+            "lib.h\n  lib.o\n    a\n  main.o\n    a ...\n"
+        );
+        assert!(deps(&graph, "main.c").is_none());
+    }
+
+    #[test]
+    fn test_export() {
+        let graph = graph();
+        assert_eq!(
+            dot(&graph),
+            r#"digraph suru {
+    "a";
+    "a" -> "main.o";
+    "a" -> "lib.o";
+    "lib.o";
+    "lib.o" -> "lib.c";
+    "lib.o" -> "lib.h";
+    "main.o";
+    "main.o" -> "main.c";
+    "main.o" -> "lib.h";
+    "lib.c" [shape=box];
+    "lib.h" [shape=box];
+    "main.c" [shape=box];
+}
+"#
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&json(&graph)).unwrap();
+        let lib = &json["targets"]["lib.o"];
+        assert_eq!(lib["dependents"], serde_json::json!(["a"]));
+        assert_eq!(
+            lib["dependencies"][1],
+            serde_json::json!({"file": "lib.h", "kind": "source", "origins": ["lib.d"]})
+        );
+        assert_eq!(json["targets"]["a"]["dependencies"][0]["kind"], "generated");
+    }
+
+    #[test]
+    fn test_recipe() {
+        let graph = graph();
+        assert_eq!(
+            recipe(&graph, "main.o").unwrap(),
+            "main.o is built by %.o < %.c
+  main.c matches %.c
+%.o < %.cpp was skipped, none of the inputs match it
+Commands:
+  gcc -c src/main.c -o build/main.o
+"
+        );
+    }
+}
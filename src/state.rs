@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+const STATE: &str = ".suru/state";
+/// Bumped whenever the format of a record changes. State written by another
+/// version is thrown away.
+const VERSION: u32 = 1;
+/// Records that are out of date are only compacted away once there are this
+/// many more of them than targets.
+const SLACK: usize = 256;
+
+/// What suru knows about the last time a target was built.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub target: String,
+    /// The commands that built it, quoted.
+    pub commands: Vec<String>,
+    pub inputs: Vec<FileHash>,
+    /// How long building it took, in seconds.
+    pub duration: f64,
+}
+
+/// A file, along with a hash of its contents, or `None` if it couldn't be read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHash {
+    pub file: String,
+    pub hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// The state suru keeps about each target between builds, in `.suru/state`
+/// inside the build directory.
+///
+/// The state is an append-only log. Its first line is a header with the
+/// version of the format, and each line after it is a [`Record`] as JSON,
+/// replacing any earlier record of the same target. Each record is written
+/// with a single append, so a crash can at worst leave the last line
+/// incomplete, which is ignored. The log is rewritten with only the latest
+/// records once enough of them are out of date.
+pub struct State {
+    state: Mutex<(HashMap<String, Record>, File)>,
+}
+
+impl State {
+    pub fn open(builddir: &Path) -> io::Result<Self> {
+        let path = builddir.join(STATE);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let (records, lines) = read_log(&path)?;
+        if lines.is_none_or(|lines| lines > records.len() + SLACK) {
+            compact(&path, &records)?;
+        }
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self {
+            state: Mutex::new((records, file)),
+        })
+    }
+
+    pub fn get(&self, target: &str) -> Option<Record> {
+        let state = self.state.lock().expect("State poisoned");
+        state.0.get(target).cloned()
+    }
+
+    /// How long each target took to build the last time it was built.
+    pub fn durations(&self) -> HashMap<String, Duration> {
+        let state = self.state.lock().expect("State poisoned");
+        durations(&state.0)
+    }
+
+    pub fn record(&self, record: Record) -> io::Result<()> {
+        let line = serde_json::to_string(&record)? + "\n";
+        let mut state = self.state.lock().expect("State poisoned");
+        let (records, file) = &mut *state;
+        file.write_all(line.as_bytes())?;
+        records.insert(record.target.clone(), record);
+        Ok(())
+    }
+}
+
+/// Reads the latest record of each target without opening the state for
+/// writing.
+pub fn read(builddir: &Path) -> io::Result<HashMap<String, Record>> {
+    Ok(read_log(&builddir.join(STATE))?.0)
+}
+
+/// Forgets everything about every target.
+pub fn remove(builddir: &Path) -> io::Result<()> {
+    match fs::remove_file(builddir.join(STATE)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub fn durations(records: &HashMap<String, Record>) -> HashMap<String, Duration> {
+    records
+        .iter()
+        .filter_map(|(target, r)| {
+            Some((
+                target.clone(),
+                Duration::try_from_secs_f64(r.duration).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// Reads the latest record of each target, along with the number of lines in
+/// the log. The number of lines is `None` if the log should be started over,
+/// because it is missing, from another version, or damaged in the middle.
+fn read_log(path: &Path) -> io::Result<(HashMap<String, Record>, Option<usize>)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((HashMap::new(), None)),
+        Err(e) => return Err(e),
+    };
+    let mut lines = contents.split_terminator('\n');
+    match lines.next().map(serde_json::from_str::<Header>) {
+        Some(Ok(header)) if header.version == VERSION => {}
+        _ => {
+            info!("Discarding the build state, it is damaged or from another version of suru");
+            return Ok((HashMap::new(), None));
+        }
+    }
+
+    let mut records = HashMap::new();
+    let mut count = 1;
+    let mut damaged = false;
+    for line in lines {
+        count += 1;
+        match serde_json::from_str::<Record>(line) {
+            Ok(record) => {
+                records.insert(record.target.clone(), record);
+            }
+            // Only the last line can be incomplete, if suru stopped partway
+            // through writing it
+            Err(_) => damaged = true,
+        }
+    }
+    // A complete log always ends in a newline
+    damaged |= !contents.ends_with('\n');
+    Ok((records, (!damaged).then_some(count)))
+}
+
+/// Replaces the log with one holding only the given records.
+fn compact(path: &Path, records: &HashMap<String, Record>) -> io::Result<()> {
+    let mut records: Vec<_> = records.values().collect();
+    records.sort_by(|a, b| a.target.cmp(&b.target));
+    let mut log = serde_json::to_string(&Header { version: VERSION })? + "\n";
+    for record in records {
+        log += &serde_json::to_string(record)?;
+        log.push('\n');
+    }
+    let temp: PathBuf = path.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(log.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(temp, path)
+}
+
+/// Hashes the contents of a file with 64 bit FNV-1a, which unlike the
+/// standard library's hasher is the same in every version of suru.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = [0; 8192];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for &byte in &buffer[..read] {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TempDir;
+
+    fn record(target: &str, command: &str) -> Record {
+        Record {
+            target: target.to_owned(),
+            commands: vec![command.to_owned()],
+            inputs: vec![FileHash {
+                file: "main.c".to_owned(),
+                hash: Some("0123456789abcdef".to_owned()),
+            }],
+            duration: 1.5,
+        }
+    }
+
+    #[test]
+    fn test_state() {
+        let temp = TempDir::new("state");
+        let builddir = temp.path();
+        let path = builddir.join(STATE);
+
+        let state = State::open(builddir).unwrap();
+        state.record(record("main.o", "gcc -O2")).unwrap();
+        state.record(record("lib.o", "gcc")).unwrap();
+        state.record(record("main.o", "gcc -O3")).unwrap();
+        drop(state);
+
+        // Stopped while writing a record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"target":"a","comm"#).unwrap();
+        drop(file);
+
+        let records = read(builddir).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records["main.o"], record("main.o", "gcc -O3"));
+        assert_eq!(durations(&records)["lib.o"], Duration::from_millis(1500));
+
+        // The damaged log is compacted, leaving the header and one line per
+        // target
+        let state = State::open(builddir).unwrap();
+        assert_eq!(state.get("main.o"), Some(record("main.o", "gcc -O3")));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        drop(state);
+
+        fs::write(&path, "{\"version\":0}\n").unwrap();
+        assert!(read(builddir).unwrap().is_empty());
+
+        remove(builddir).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let temp = TempDir::new("hash");
+        let path = temp.path().join("a");
+        fs::write(&path, "a").unwrap();
+        assert_eq!(hash_file(&path).unwrap(), "af63dc4c8601ec8c");
+    }
+}
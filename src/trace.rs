@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde_json::json;
+
+use crate::util::Usage;
+
+/// A command run while building a target.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub target: String,
+    pub command: String,
+    /// The worker thread that ran the command.
+    pub worker: usize,
+    /// When the command started and finished, relative to the start of the build.
+    pub start: Duration,
+    pub end: Duration,
+    /// The exit code, or `None` if the command was killed by a signal or could
+    /// not be run.
+    pub status: Option<i32>,
+    pub usage: Option<Usage>,
+}
+
+/// Every command run during a build, for `--trace`.
+pub struct Trace {
+    start: Instant,
+    spans: Mutex<Vec<Span>>,
+}
+
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static WORKER: usize = NEXT_WORKER.fetch_add(1, Relaxed);
+}
+
+/// A small number identifying the current thread in traces.
+pub fn worker() -> usize {
+    WORKER.with(|w| *w)
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            spans: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The time since the trace started.
+    pub fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn record(&self, span: Span) {
+        self.spans.lock().expect("Trace poisoned").push(span);
+    }
+
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans.lock().expect("Trace poisoned").clone()
+    }
+
+    /// Writes the trace in the Chrome trace event format, which Perfetto and
+    /// chrome://tracing can load.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let spans = self.spans();
+        let mut workers: Vec<_> = spans.iter().map(|s| s.worker).collect();
+        workers.sort();
+        workers.dedup();
+
+        let names = workers.iter().map(|w| {
+            json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": w,
+                "args": { "name": format!("worker {}", w) },
+            })
+        });
+        let commands = spans.iter().map(|s| {
+            let mut args = json!({
+                "command": s.command,
+                "status": s.status,
+            });
+            if let Some(usage) = s.usage {
+                args["cpu_ms"] = json!(usage.cpu.as_secs_f64() * 1000.0);
+                args["max_rss_kib"] = json!(usage.max_rss);
+            }
+            json!({
+                "name": s.target,
+                "cat": "command",
+                "ph": "X",
+                "ts": s.start.as_micros() as u64,
+                "dur": (s.end - s.start).as_micros() as u64,
+                "pid": 1,
+                "tid": s.worker,
+                "args": args,
+            })
+        });
+        let trace = json!({
+            "traceEvents": names.chain(commands).collect::<Vec<_>>(),
+            "displayTimeUnit": "ms",
+        });
+        serde_json::to_writer(BufWriter::new(File::create(path)?), &trace)?;
+        Ok(())
+    }
+
+    /// The time spent running commands for each target.
+    fn durations(&self) -> HashMap<String, Duration> {
+        let mut durations: HashMap<String, Duration> = HashMap::new();
+        for span in self.spans.lock().expect("Trace poisoned").iter() {
+            *durations.entry(span.target.clone()).or_default() += span.end - span.start;
+        }
+        durations
+    }
+
+    /// Describes the chain of dependencies that took the longest to build, and
+    /// the slowest targets. `dependencies` maps each target to the targets it
+    /// depends on.
+    pub fn summary(&self, dependencies: &HashMap<String, Vec<String>>) -> String {
+        let durations = self.durations();
+        let mut out = String::new();
+
+        let path = critical_path(&durations, dependencies);
+        let total: Duration = path.iter().map(|t| durations[t]).sum();
+        writeln!(out, "Critical path ({}):", seconds(total)).unwrap();
+        for target in &path {
+            writeln!(out, "  {:>8}  {}", seconds(durations[target]), target).unwrap();
+        }
+
+        let mut slowest: Vec<_> = durations.iter().collect();
+        slowest.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        out.push_str("Slowest targets:\n");
+        for (target, duration) in slowest.into_iter().take(10) {
+            writeln!(out, "  {:>8}  {}", seconds(*duration), target).unwrap();
+        }
+        out
+    }
+}
+
+fn seconds(d: Duration) -> String {
+    format!("{:.2}s", d.as_secs_f64())
+}
+
+/// Finds the chain of dependencies with the longest total duration, in the order
+/// they were built. Targets without a duration weren't built, and count as free.
+fn critical_path(
+    durations: &HashMap<String, Duration>,
+    dependencies: &HashMap<String, Vec<String>>,
+) -> Vec<String> {
+    // The longest time to finish each target, and the dependency that decided it
+    fn finish<'a>(
+        target: &'a str,
+        durations: &HashMap<String, Duration>,
+        dependencies: &'a HashMap<String, Vec<String>>,
+        memo: &mut HashMap<&'a str, (Duration, Option<&'a str>)>,
+    ) -> Duration {
+        if let Some((time, _)) = memo.get(target) {
+            return *time;
+        }
+        // Guards against cycles, which would otherwise recurse forever
+        memo.insert(target, (Duration::ZERO, None));
+        let mut slowest = (Duration::ZERO, None);
+        for dep in dependencies.get(target).into_iter().flatten() {
+            let time = finish(dep, durations, dependencies, memo);
+            if time > slowest.0 {
+                slowest = (time, Some(dep.as_str()));
+            }
+        }
+        let own = durations.get(target).copied().unwrap_or_default();
+        memo.insert(target, (slowest.0 + own, slowest.1));
+        slowest.0 + own
+    }
+
+    let mut memo = HashMap::new();
+    let mut names: Vec<_> = dependencies.keys().collect();
+    names.sort();
+    let mut end: Option<(&str, Duration)> = None;
+    for name in names {
+        let time = finish(name, durations, dependencies, &mut memo);
+        if end.is_none_or(|(_, t)| time > t) {
+            end = Some((name, time));
+        }
+    }
+
+    let mut path = Vec::new();
+    let mut next = end.filter(|(_, t)| !t.is_zero()).map(|(name, _)| name);
+    while let Some(target) = next {
+        if durations.contains_key(target) {
+            path.push(target.to_owned());
+        }
+        next = memo[target].1;
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_critical_path() {
+        let dependencies = HashMap::from([
+            (
+                "a".to_string(),
+                vec!["main.o".to_string(), "lib.o".to_string()],
+            ),
+            ("main.o".to_string(), vec!["config.h".to_string()]),
+            ("lib.o".to_string(), vec!["config.h".to_string()]),
+            ("config.h".to_string(), vec![]),
+        ]);
+        let durations = HashMap::from([
+            ("a".to_string(), Duration::from_secs(1)),
+            ("main.o".to_string(), Duration::from_secs(2)),
+            ("lib.o".to_string(), Duration::from_secs(3)),
+        ]);
+        assert_eq!(
+            critical_path(&durations, &dependencies),
+            ["lib.o".to_string(), "a".to_string()]
+        );
+        assert!(critical_path(&HashMap::new(), &dependencies).is_empty());
+    }
+}
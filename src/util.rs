@@ -1,3 +1,13 @@
+use std::{
+    ffi::OsString,
+    io,
+    process::{Command, Output},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use crate::interrupt::{self, Running};
+
 pub fn append_string(s: &mut String, end: &str) {
     s.reserve(end.len() + 1);
     if !s.is_empty() {
@@ -14,7 +24,178 @@ pub fn remove_suffix(file: &str) -> &str {
     file.split_once('.').unwrap_or((file, "")).0
 }
 
+/// Renders a command the way it would be typed into a shell.
+pub fn quote_command(cmd: &[OsString]) -> String {
+    cmd.iter()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "'\"\\$".contains(c)) {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            } else {
+                arg.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the one minute system load average, if the platform provides one.
+#[cfg(unix)]
+pub fn load_average() -> Option<f64> {
+    let mut avg = [0f64; 1];
+    // SAFETY: `avg` has room for the single sample requested.
+    let n = unsafe { libc::getloadavg(avg.as_mut_ptr(), 1) };
+    (n == 1).then_some(avg[0])
+}
+
+#[cfg(not(unix))]
+pub fn load_average() -> Option<f64> {
+    None
+}
+
+/// Resources used by a finished child process.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// User and system CPU time.
+    pub cpu: Duration,
+    /// Peak resident set size, in KiB.
+    pub max_rss: u64,
+}
+
+/// Runs a command like [`Command::output`], also returning the resources the
+/// child used where the platform reports them. Once signals are handled, the
+/// child leads its own process group, which is passed on any signal that
+/// interrupts suru.
+#[cfg(unix)]
+pub fn output_with_usage(command: &mut Command) -> io::Result<(Output, Option<Usage>)> {
+    use std::{
+        io::Read,
+        os::unix::process::{CommandExt, ExitStatusExt},
+        process::Stdio,
+    };
+
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if interrupt::installed() {
+        command.process_group(0);
+    }
+    let mut child = command.spawn()?;
+    let _running = Running::new(child.id());
+    // Both pipes are drained at once, so the child can't block on a full one
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+    let mut stdout = Vec::new();
+    child.stdout.take().unwrap().read_to_end(&mut stdout)?;
+    let stderr = stderr.join().expect("Unable to read stderr")?;
+
+    let mut status = 0;
+    // SAFETY: rusage is plain old data, so all zeroes is a valid value.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: the child hasn't been waited on yet, so its pid is still ours.
+        if unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) } != -1 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    // macOS reports bytes rather than KiB
+    let max_rss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64 / 1024
+    } else {
+        usage.ru_maxrss as u64
+    };
+    let output = Output {
+        status: std::process::ExitStatus::from_raw(status),
+        stdout,
+        stderr,
+    };
+    let usage = Usage {
+        cpu: time(usage.ru_utime) + time(usage.ru_stime),
+        max_rss,
+    };
+    Ok((output, Some(usage)))
+}
+
+#[cfg(not(unix))]
+pub fn output_with_usage(command: &mut Command) -> io::Result<(Output, Option<Usage>)> {
+    Ok((command.output()?, None))
+}
+
 #[cfg(test)]
 pub fn make_svec(s: &[&str]) -> Vec<String> {
     s.iter().map(|s| s.to_string()).collect()
 }
+
+/// A directory for a test to work in, which is removed when it is dropped,
+/// even if the test fails.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates an empty directory named after `name`, which no other test in
+    /// any running process uses.
+    pub fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "suru-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quote_command() {
+        let cmd: Vec<OsString> = ["gcc", "-DNAME=a b", "it's", "-c"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+        assert_eq!(quote_command(&cmd), r"gcc '-DNAME=a b' 'it'\''s' -c");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_output_with_usage() {
+        let (output, usage) =
+            output_with_usage(Command::new("sh").args(["-c", "echo out; echo err >&2; exit 3"]))
+                .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+        assert!(usage.unwrap().max_rss > 0);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io::{self, ErrorKind},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::warn;
+
+/// Watches a set of files for changes, for `suru --watch`.
+///
+/// The directories containing the files are watched rather than the files
+/// themselves, so that files which are replaced by renaming over them, or that
+/// are deleted and created again, are still seen.
+pub struct Watcher {
+    inotify: Inotify,
+    /// Each watched directory, and the names of the watched files in it.
+    dirs: HashMap<WatchDescriptor, (PathBuf, HashSet<OsString>)>,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            inotify: Inotify::init()?,
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Replaces the set of watched files.
+    pub fn watch(&mut self, files: impl IntoIterator<Item = PathBuf>) {
+        let mut dirs: HashMap<PathBuf, HashSet<OsString>> = HashMap::new();
+        for file in files {
+            let (Some(parent), Some(name)) = (file.parent(), file.file_name()) else {
+                continue;
+            };
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            dirs.entry(parent.to_path_buf())
+                .or_default()
+                .insert(name.to_owned());
+        }
+
+        let mask = WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+        let mut watched: HashMap<_, (_, HashSet<_>)> = HashMap::new();
+        for (dir, names) in dirs {
+            // Adding a directory that is already watched gives back its old
+            // descriptor, which is also how two spellings of a path are merged
+            match self.inotify.watches().add(&dir, mask) {
+                Ok(wd) => watched
+                    .entry(wd)
+                    .or_insert((dir, HashSet::new()))
+                    .1
+                    .extend(names),
+                Err(e) => warn!("Unable to watch {:?} for changes: {}", dir, e),
+            }
+        }
+        for wd in self.dirs.keys() {
+            if !watched.contains_key(wd) {
+                let _ = self.inotify.watches().remove(wd.clone());
+            }
+        }
+        self.dirs = watched;
+    }
+
+    /// Returns the watched files that changed since this was last called,
+    /// without blocking.
+    pub fn changes(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut buffer = [0; 4096];
+        let mut changed = Vec::new();
+        loop {
+            let events = match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(changed),
+                Err(e) => return Err(e),
+            };
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    // Events were dropped, so anything could have changed
+                    warn!("Too many changes to track, treating every file as changed");
+                    changed.extend(
+                        self.dirs
+                            .values()
+                            .flat_map(|(dir, names)| names.iter().map(move |name| dir.join(name))),
+                    );
+                    continue;
+                }
+                let (Some((dir, names)), Some(name)) = (self.dirs.get(&event.wd), event.name)
+                else {
+                    continue;
+                };
+                if names.contains(name) {
+                    changed.push(dir.join(name));
+                }
+            }
+        }
+    }
+
+    /// Blocks until a watched file changes, then keeps collecting changes until
+    /// none have happened for `debounce`. Returns every file that changed.
+    pub fn wait(&mut self, debounce: Duration) -> io::Result<Vec<PathBuf>> {
+        let mut changed = self.changes()?;
+        while changed.is_empty() {
+            self.poll(None)?;
+            changed.extend(self.changes()?);
+        }
+        while self.poll(Some(debounce))? {
+            changed.extend(self.changes()?);
+        }
+        changed.sort();
+        changed.dedup();
+        Ok(changed)
+    }
+
+    /// Waits for events to be readable, returning false if the timeout passed
+    /// first.
+    fn poll(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        loop {
+            // SAFETY: fd is a valid pollfd, and poll is told there is exactly
+            // one of it.
+            match unsafe { libc::poll(&mut fd, 1, timeout) } {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                n => return Ok(n > 0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn test_changes() {
+        let temp = TempDir::new("watch");
+        let dir = temp.path();
+        fs::write(dir.join("main.c"), "").unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher.watch([dir.join("main.c"), dir.join("config.h")]);
+        assert!(watcher.changes().unwrap().is_empty());
+
+        fs::write(dir.join("other.c"), "").unwrap();
+        fs::write(dir.join("main.c"), "int main;").unwrap();
+        // Newly created files are seen too
+        fs::write(dir.join("config.h"), "").unwrap();
+        assert_eq!(
+            watcher.wait(Duration::from_millis(10)).unwrap(),
+            [dir.join("config.h"), dir.join("main.c")]
+        );
+    }
+}